@@ -59,14 +59,14 @@ pub fn main() {
 
 pub fn prev_seek_next() {
     let num = 32;
-    let mut datas = (0..num).into_iter().map(|v| v * 2).collect::<Vec<_>>();
+    let mut datas = (0..num).map(|v| v * 2).collect::<Vec<_>>();
 
     datas.shuffle(&mut rand::thread_rng());
 
     let mut btree = mem_btree::BTree::new(4);
 
     for i in datas.iter() {
-        btree.put(i.clone(), i.clone());
+        btree.put(*i, *i);
     }
 
     let mut iter = btree.iter();
@@ -88,14 +88,14 @@ pub fn prev_seek_next() {
 
 pub fn seek_next() {
     let num = 32;
-    let mut datas = (0..num).into_iter().map(|v| v * 2).collect::<Vec<_>>();
+    let mut datas = (0..num).map(|v| v * 2).collect::<Vec<_>>();
 
     datas.shuffle(&mut rand::thread_rng());
 
     let mut btree = mem_btree::BTree::new(4);
 
     for i in datas.iter() {
-        btree.put(i.clone(), i.clone());
+        btree.put(*i, *i);
     }
 
     let mut iter = btree.iter();
@@ -117,14 +117,14 @@ pub fn seek_next() {
 
 pub fn prev_iter() {
     let num = 1024;
-    let mut datas = (0..num).into_iter().map(|v| v).collect::<Vec<_>>();
+    let mut datas = (0..num).collect::<Vec<_>>();
 
     datas.shuffle(&mut rand::thread_rng());
 
     let mut btree = mem_btree::BTree::new(32);
 
     for i in datas.iter() {
-        btree.put(i.clone(), i.clone());
+        btree.put(*i, *i);
     }
 
     let mut iter = btree.iter();
@@ -136,14 +136,14 @@ pub fn prev_iter() {
 
 pub fn next_iter() {
     let num = 1024;
-    let mut datas = (0..num).into_iter().map(|v| v).collect::<Vec<_>>();
+    let mut datas = (0..num).collect::<Vec<_>>();
 
     datas.shuffle(&mut rand::thread_rng());
 
     let mut btree = mem_btree::BTree::new(32);
 
     for i in datas.iter() {
-        btree.put(i.clone(), i.clone());
+        btree.put(*i, *i);
     }
 
     println!("{:#?}", btree);
@@ -158,14 +158,14 @@ pub fn next_iter() {
 pub fn split_off() {
     for k in 0..33 {
         let num = 32;
-        let mut datas = (0..num).into_iter().map(|v| v).collect::<Vec<_>>();
+        let mut datas = (0..num).collect::<Vec<_>>();
 
         datas.shuffle(&mut rand::thread_rng());
 
         let mut btree = mem_btree::BTree::new(4);
 
         for i in datas.iter() {
-            btree.put(i.clone(), i.clone());
+            btree.put(*i, *i);
         }
 
         let right = btree.split_off(&k);
@@ -176,14 +176,14 @@ pub fn split_off() {
 
 pub fn remove() {
     let num = 1024 * 1024;
-    let mut datas = (0..num).into_iter().map(|v| v).collect::<Vec<_>>();
+    let mut datas = (0..num).collect::<Vec<_>>();
 
     datas.shuffle(&mut rand::thread_rng());
 
     let mut btree = mem_btree::BTree::new(4);
 
     for i in datas.iter() {
-        btree.put(i.clone(), i.clone());
+        btree.put(*i, *i);
     }
 
     println!("{:?}", btree.len());
@@ -0,0 +1,236 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::{
+    comparator::{after_start, before_end},
+    *,
+};
+
+/// A value summarizing the items of a subtree, such as a count, a sum or a max.
+///
+/// Every node keeps the aggregate of its items, it is computed when the node
+/// is built, so `BTree::aggregate` answers for a range in O(log n).
+/// `combine` must be associative and `identity` neutral to it, items are
+/// combined in key order.
+pub trait Aggregate<K, V>: Clone {
+    /// the aggregate of no items
+    fn identity() -> Self;
+
+    /// the aggregate of a single item
+    fn from_item(key: &K, value: &V) -> Self;
+
+    /// the aggregate of the items of self followed by the items of other
+    fn combine(&self, other: &Self) -> Self;
+}
+
+/// no aggregate, the default of `BTree`
+impl<K, V> Aggregate<K, V> for () {
+    #[inline]
+    fn identity() -> Self {}
+
+    #[inline]
+    fn from_item(_: &K, _: &V) -> Self {}
+
+    #[inline]
+    fn combine(&self, _: &Self) -> Self {}
+}
+
+impl<K, V, A, C> BTree<K, V, A, C>
+where
+    A: Aggregate<K, V>,
+    C: Comparator<K>,
+{
+    /// The aggregate of the items in the range in O(log n),
+    /// the nodes inside the range are not visited, only their cached aggregate is used.
+    /// Expired items count until `expir` removes them
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::{Aggregate, BTree};
+    ///
+    /// #[derive(Clone)]
+    /// struct Sum(u64);
+    ///
+    /// impl<K> Aggregate<K, u64> for Sum {
+    ///     fn identity() -> Self {
+    ///         Sum(0)
+    ///     }
+    ///     fn from_item(_: &K, v: &u64) -> Self {
+    ///         Sum(*v)
+    ///     }
+    ///     fn combine(&self, other: &Self) -> Self {
+    ///         Sum(self.0 + other.0)
+    ///     }
+    /// }
+    ///
+    /// let mut btree = BTree::<u32, u64, Sum>::with_aggregate(4);
+    /// for i in 0..100 {
+    ///     btree.put(i, i as u64);
+    /// }
+    /// assert_eq!(btree.aggregate(10..20).0, (10..20).sum());
+    /// assert_eq!(btree.aggregate(..).0, (0..100).sum());
+    /// ```
+    pub fn aggregate<R: RangeBounds<K>>(&self, range: R) -> A {
+        aggregate_range(&self.root, &range, None)
+    }
+}
+
+/// the aggregate of the items of node in range, all of them are before upper
+fn aggregate_range<K, V, A, C, R>(node: &N<K, V, A, C>, range: &R, upper: Option<&K>) -> A
+where
+    A: Aggregate<K, V>,
+    C: Comparator<K>,
+    R: RangeBounds<K>,
+{
+    match node.as_ref() {
+        BTreeType::Leaf(leaf) => leaf
+            .items
+            .iter()
+            .filter(|i| after_start::<K, C, R>(range, &i.0) && before_end::<K, C, R>(range, &i.0))
+            .fold(A::identity(), |a, i| a.combine(&A::from_item(&i.0, &i.1))),
+        BTreeType::Node(n) => {
+            let mut aggregate = A::identity();
+            for (i, child) in n.children.iter().enumerate() {
+                let Some(first) = child.key().map(|item| &item.0) else {
+                    continue;
+                };
+                // the keys of the child are before the first key of the next one
+                let next = match n.children.get(i + 1) {
+                    Some(c) => c.key().map(|item| &item.0),
+                    None => upper,
+                };
+
+                if !before_end::<K, C, R>(range, first) {
+                    break;
+                }
+                if matches!(next, Some(next) if before_start::<K, C, R>(range, next)) {
+                    continue;
+                }
+
+                if covers::<K, C, R>(range, first, next) {
+                    aggregate = aggregate.combine(child.aggregate());
+                } else {
+                    aggregate = aggregate.combine(&aggregate_range(child, range, next));
+                }
+            }
+            aggregate
+        }
+    }
+}
+
+/// the keys before next are before the range
+fn before_start<K, C: Comparator<K>, R: RangeBounds<K>>(range: &R, next: &K) -> bool {
+    match range.start_bound() {
+        Bound::Included(s) | Bound::Excluded(s) => C::cmp(next, s).is_le(),
+        Bound::Unbounded => false,
+    }
+}
+
+/// the keys from first and before next are all in the range
+fn covers<K, C: Comparator<K>, R: RangeBounds<K>>(range: &R, first: &K, next: Option<&K>) -> bool {
+    let end = match (range.end_bound(), next) {
+        (Bound::Unbounded, _) => true,
+        (Bound::Included(e) | Bound::Excluded(e), Some(next)) => C::cmp(next, e).is_le(),
+        (_, None) => false,
+    };
+    after_start::<K, C, R>(range, first) && end
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, ops::Bound};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::Aggregate;
+    use crate::{BTree, BatchWrite};
+
+    /// the count, the sum and the max of the values, the keys in order
+    #[derive(Clone, Debug, PartialEq)]
+    struct Stats {
+        count: usize,
+        sum: u64,
+        max: Option<u32>,
+        keys: Vec<i32>,
+    }
+
+    impl Aggregate<i32, u32> for Stats {
+        fn identity() -> Self {
+            Stats {
+                count: 0,
+                sum: 0,
+                max: None,
+                keys: Vec::new(),
+            }
+        }
+
+        fn from_item(k: &i32, v: &u32) -> Self {
+            Stats {
+                count: 1,
+                sum: *v as u64,
+                max: Some(*v),
+                keys: vec![*k],
+            }
+        }
+
+        fn combine(&self, other: &Self) -> Self {
+            Stats {
+                count: self.count + other.count,
+                sum: self.sum + other.sum,
+                max: self.max.max(other.max),
+                keys: self.keys.iter().chain(other.keys.iter()).cloned().collect(),
+            }
+        }
+    }
+
+    fn bound(rng: &mut StdRng) -> Bound<i32> {
+        match rng.gen_range(0..3) {
+            0 => Bound::Included(rng.gen_range(-10..1010)),
+            1 => Bound::Excluded(rng.gen_range(-10..1010)),
+            _ => Bound::Unbounded,
+        }
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut btree = BTree::<i32, u32, Stats>::with_aggregate(4);
+        let mut map = BTreeMap::new();
+
+        for round in 0..30 {
+            for _ in 0..100 {
+                let k = rng.gen_range(0..1000);
+                if rng.gen_bool(0.3) {
+                    btree.remove(&k);
+                    map.remove(&k);
+                } else {
+                    let v = rng.gen::<u32>();
+                    btree.put(k, v);
+                    map.insert(k, v);
+                }
+            }
+            if round % 5 == 0 {
+                let mut bw = BatchWrite::default();
+                let start = rng.gen_range(0..1000);
+                bw.delete_range(start..start + 100);
+                map.retain(|k, _| !(start..start + 100).contains(k));
+                for _ in 0..50 {
+                    let (k, v) = (rng.gen_range(0..1000), rng.gen::<u32>());
+                    bw.put(k, v);
+                    map.insert(k, v);
+                }
+                btree.write(bw);
+            }
+
+            for _ in 0..50 {
+                let range = (bound(&mut rng), bound(&mut rng));
+                let expected = map
+                    .iter()
+                    .filter(|(k, _)| std::ops::RangeBounds::contains(&range, *k))
+                    .fold(Stats::identity(), |a, (k, v)| {
+                        a.combine(&Stats::from_item(k, v))
+                    });
+                assert_eq!(btree.aggregate(range), expected);
+            }
+            assert_eq!(btree.aggregate(..).count, map.len());
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use std::{cmp::Ordering, ops::Bound, sync::Arc};
+
+use crate::*;
+
+/// Which keys of two trees a set operation keeps
+#[derive(Clone, Copy)]
+pub(crate) enum SetOp {
+    Union,
+    Intersection,
+    Difference,
+    SymmetricDifference,
+}
+
+impl SetOp {
+    /// whether a key is kept, given whether the left and the right tree hold it
+    pub(crate) fn keep(self, in_left: bool, in_right: bool) -> bool {
+        match self {
+            SetOp::Union => in_left || in_right,
+            SetOp::Intersection => in_left && in_right,
+            SetOp::Difference => in_left && !in_right,
+            SetOp::SymmetricDifference => in_left != in_right,
+        }
+    }
+}
+
+/// the item kept for a key in both trees
+type Resolve<'a, K, V> = &'a dyn Fn(&Item<K, V>, &Item<K, V>) -> Item<K, V>;
+
+impl<K, V, A, C> BTree<K, V, A, C>
+where
+    A: Aggregate<K, V>,
+    C: Comparator<K>,
+{
+    /// The items of self and other, f gives the value of a key in both of them,
+    /// it keeps the ttl of self.
+    /// The subtrees of one tree outside the key range of the other are reused as they are
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let mut a = BTree::new(32);
+    /// let mut b = BTree::new(32);
+    /// for i in 0..10 {
+    ///     a.put(i, 1);
+    ///     b.put(i + 5, 10);
+    /// }
+    /// let union = a.union_with(&b, |_, x, y| x + y);
+    /// assert_eq!(union.len(), 15);
+    /// assert_eq!(union.get(&4), Some(&1));
+    /// assert_eq!(union.get(&5), Some(&11));
+    /// assert_eq!(union.get(&14), Some(&10));
+    /// ```
+    pub fn union_with<F>(&self, other: &Self, f: F) -> Self
+    where
+        K: Clone,
+        F: Fn(&K, &V, &V) -> V,
+    {
+        self.combine(other, SetOp::Union, &|a, b| {
+            Arc::new((a.0.clone(), f(&a.0, &a.1, &b.1), a.2))
+        })
+    }
+
+    /// The keys in both self and other, f gives their values, it keeps the ttl of self
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let mut a = BTree::new(32);
+    /// let mut b = BTree::new(32);
+    /// for i in 0..10 {
+    ///     a.put(i, 1);
+    ///     b.put(i + 5, 10);
+    /// }
+    /// let both = a.intersection_with(&b, |_, x, y| x + y);
+    /// assert_eq!(both.iter().map(|i| (i.0, i.1)).collect::<Vec<_>>(),
+    ///     (5..10).map(|k| (k, 11)).collect::<Vec<_>>());
+    /// ```
+    pub fn intersection_with<F>(&self, other: &Self, f: F) -> Self
+    where
+        K: Clone,
+        F: Fn(&K, &V, &V) -> V,
+    {
+        self.combine(other, SetOp::Intersection, &|a, b| {
+            Arc::new((a.0.clone(), f(&a.0, &a.1, &b.1), a.2))
+        })
+    }
+
+    /// The items of self whose keys are not in other
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let mut a = BTree::new(32);
+    /// let mut b = BTree::new(32);
+    /// for i in 0..10 {
+    ///     a.put(i, i);
+    ///     b.put(i + 5, i);
+    /// }
+    /// assert_eq!(a.difference(&b).iter().map(|i| i.0).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+    /// ```
+    pub fn difference(&self, other: &Self) -> Self {
+        self.combine(other, SetOp::Difference, &|a, _| a.clone())
+    }
+
+    /// The items of self and other whose keys are in only one of them
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let mut a = BTree::new(32);
+    /// let mut b = BTree::new(32);
+    /// for i in 0..10 {
+    ///     a.put(i, i);
+    ///     b.put(i + 5, i);
+    /// }
+    /// assert_eq!(a.symmetric_difference(&b).iter().map(|i| i.0).collect::<Vec<_>>(),
+    ///     vec![0, 1, 2, 3, 4, 10, 11, 12, 13, 14]);
+    /// ```
+    pub fn symmetric_difference(&self, other: &Self) -> Self {
+        self.combine(other, SetOp::SymmetricDifference, &|a, _| a.clone())
+    }
+
+    /// a tree with the settings of self holding the keys op keeps
+    pub(crate) fn combine(&self, other: &Self, op: SetOp, resolve: Resolve<K, V>) -> Self {
+        let root = combine_nodes(self.m, &self.root, &other.root, op, resolve);
+        self.with_root(BTreeType::trim(root))
+    }
+}
+
+fn combine_nodes<K, V, A, C>(
+    m: usize,
+    left: &N<K, V, A, C>,
+    right: &N<K, V, A, C>,
+    op: SetOp,
+    resolve: Resolve<K, V>,
+) -> N<K, V, A, C>
+where
+    A: Aggregate<K, V>,
+    C: Comparator<K>,
+{
+    let keep_left = op.keep(true, false);
+    let keep_right = op.keep(false, true);
+    let empty = || Leaf::instance(Vec::new());
+
+    if left.is_empty() || right.is_empty() {
+        return match (keep_left && !left.is_empty(), keep_right) {
+            (true, _) => left.clone(),
+            (false, true) => right.clone(),
+            _ => empty(),
+        };
+    }
+
+    // the key ranges do not overlap, the trees are kept or dropped whole
+    let (left_first, left_last) = (&left.key().unwrap().0, &left.max().unwrap().0);
+    let (right_first, right_last) = (&right.key().unwrap().0, &right.max().unwrap().0);
+    let left_before = C::cmp(left_last, right_first).is_lt();
+    if left_before || C::cmp(right_last, left_first).is_lt() {
+        let left = if keep_left { left.clone() } else { empty() };
+        let right = if keep_right { right.clone() } else { empty() };
+        return if left_before {
+            BTreeType::concat(m, left, right)
+        } else {
+            BTreeType::concat(m, right, left)
+        };
+    }
+
+    match (&**left, &**right) {
+        (BTreeType::Leaf(l), BTreeType::Leaf(r)) => {
+            let items = merge_items::<K, V, C>(&l.items, &r.items, op, resolve);
+            items
+                .chunks(m)
+                .map(|c| Leaf::instance(c.to_vec()))
+                .fold(empty(), |tree, leaf| BTreeType::concat(m, tree, leaf))
+        }
+        _ => {
+            // cut the lower tree at the first keys of the children of the higher one
+            let divide_left = left.height() >= right.height();
+            let (divider, other) = if divide_left {
+                (left, right)
+            } else {
+                (right, left)
+            };
+            let BTreeType::Node(node) = &**divider else {
+                unreachable!()
+            };
+
+            let mut rest = other.clone();
+            let mut result = empty();
+            for (i, child) in node.children.iter().enumerate() {
+                let slice = match node.children.get(i + 1) {
+                    Some(next) => {
+                        let (slice, after) =
+                            rest.split_bound(Bound::Included(&next.key().unwrap().0));
+                        rest = after;
+                        slice
+                    }
+                    None => rest.clone(),
+                };
+                let part = if divide_left {
+                    combine_nodes(m, child, &slice, op, resolve)
+                } else {
+                    combine_nodes(m, &slice, child, op, resolve)
+                };
+                result = BTreeType::concat(m, result, part);
+            }
+            result
+        }
+    }
+}
+
+/// merge the sorted items of two leaves, keeping the keys op keeps
+fn merge_items<K, V, C>(
+    left: &[Item<K, V>],
+    right: &[Item<K, V>],
+    op: SetOp,
+    resolve: Resolve<K, V>,
+) -> Vec<Item<K, V>>
+where
+    C: Comparator<K>,
+{
+    let mut items = Vec::with_capacity(left.len() + right.len());
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() || j < right.len() {
+        let ord = match (left.get(i), right.get(j)) {
+            (Some(a), Some(b)) => C::cmp(&a.0, &b.0),
+            (Some(_), None) => Ordering::Less,
+            _ => Ordering::Greater,
+        };
+        match ord {
+            Ordering::Equal => {
+                if op.keep(true, true) {
+                    items.push(resolve(&left[i], &right[j]));
+                }
+                i += 1;
+                j += 1;
+            }
+            Ordering::Less => {
+                if op.keep(true, false) {
+                    items.push(left[i].clone());
+                }
+                i += 1;
+            }
+            Ordering::Greater => {
+                if op.keep(false, true) {
+                    items.push(right[j].clone());
+                }
+                j += 1;
+            }
+        }
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{BTree, BTreeType, N};
+
+    fn random_tree(rng: &mut StdRng, tag: u32) -> (BTree<i32, u32>, BTreeMap<i32, u32>) {
+        let mut tree = BTree::new(rng.gen_range(3..8));
+        let mut map = BTreeMap::new();
+        let (start, len) = (rng.gen_range(0..500), rng.gen_range(1..600));
+        for _ in 0..rng.gen_range(0..400) {
+            let k = rng.gen_range(start..start + len);
+            if rng.gen_bool(0.2) {
+                tree.remove(&k);
+                map.remove(&k);
+            } else {
+                tree.put(k, tag);
+                map.insert(k, tag);
+            }
+        }
+        (tree, map)
+    }
+
+    fn items(tree: &BTree<i32, u32>) -> Vec<(i32, u32)> {
+        tree.iter().map(|i| (i.0, i.1)).collect()
+    }
+
+    #[test]
+    fn test_set_algebra_same_as_btreemap() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..200 {
+            let (a, ma) = random_tree(&mut rng, 1);
+            let (b, mb) = random_tree(&mut rng, 10);
+
+            let mut union = mb.clone();
+            for (k, v) in ma.iter() {
+                *union.entry(*k).or_insert(0) += v;
+            }
+            let intersection: BTreeMap<_, _> = ma
+                .iter()
+                .filter_map(|(k, v)| mb.get(k).map(|w| (*k, v + w)))
+                .collect();
+            let difference: BTreeMap<_, _> = ma
+                .iter()
+                .filter(|(k, _)| !mb.contains_key(k))
+                .map(|(k, v)| (*k, *v))
+                .collect();
+            let symmetric_difference: BTreeMap<_, _> = ma
+                .iter()
+                .chain(mb.iter())
+                .filter(|(k, _)| ma.contains_key(k) != mb.contains_key(k))
+                .map(|(k, v)| (*k, *v))
+                .collect();
+
+            let check = |tree: BTree<i32, u32>, expected: BTreeMap<i32, u32>| {
+                assert_eq!(items(&tree), expected.into_iter().collect::<Vec<_>>());
+                assert_eq!(tree.len(), tree.iter().count());
+            };
+            check(a.union_with(&b, |_, x, y| x + y), union);
+            check(a.intersection_with(&b, |_, x, y| x + y), intersection);
+            check(a.difference(&b), difference);
+            check(a.symmetric_difference(&b), symmetric_difference);
+        }
+    }
+
+    /// the leaves of the tree in order
+    fn leaves(node: &N<i32, u32>, out: &mut Vec<N<i32, u32>>) {
+        match &**node {
+            BTreeType::Leaf(_) => out.push(node.clone()),
+            BTreeType::Node(n) => n.children.iter().for_each(|c| leaves(c, out)),
+        }
+    }
+
+    #[test]
+    fn test_set_algebra_reuses_subtrees() {
+        let mut a = BTree::new(4);
+        let mut b = BTree::new(4);
+        for i in 0..1000 {
+            a.put(i, i as u32);
+            b.put(i + 5000, i as u32);
+        }
+
+        // disjoint trees are not visited
+        assert!(Arc::ptr_eq(&a.difference(&b).root, &a.root));
+        assert!(a.intersection_with(&b, |_, x, _| *x).is_empty());
+        assert_eq!(a.union_with(&b, |_, x, _| *x).len(), 2000);
+
+        // a key in the middle of b only rebuilds the leaf holding it,
+        // concat may merge it with a neighbour
+        let mut c = BTree::new(4);
+        c.put(5500, 0);
+        let union = b.union_with(&c, |_, x, _| *x);
+        assert_eq!(union.get(&5500), Some(&500));
+
+        let (mut old, mut new) = (Vec::new(), Vec::new());
+        leaves(&b.root, &mut old);
+        leaves(&union.root, &mut new);
+        let shared = new
+            .iter()
+            .filter(|n| old.iter().any(|o| Arc::ptr_eq(n, o)))
+            .count();
+        assert!(shared + 2 >= old.len());
+    }
+}
@@ -1,44 +1,567 @@
-use std::{collections::BTreeMap, ops::Add, time::Duration};
+use std::{
+    cmp::Ordering,
+    collections::{btree_map::Entry, BTreeMap},
+    fmt::Debug,
+    marker::PhantomData,
+    ops::{Add, Range},
+    time::Duration,
+};
 
-use crate::now;
+use crate::{now, Comparator, Item, OrdComparator};
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "V: serde::Serialize",
+        deserialize = "V: serde::Deserialize<'de> + PartialEq"
+    ))
+)]
 pub enum Action<V> {
     Put(V, Option<Duration>),
     Delete,
+    /// put the value only if the key is absent
+    PutIfAbsent(V, Option<Duration>),
+    /// put the value only if `eq(current, expected)` holds for the current value.
+    /// `eq` is not serialized, a deserialized action compares by `PartialEq`,
+    /// so an action with another `eq` does not keep its meaning through serde
+    CompareAndSet {
+        expected: V,
+        value: V,
+        ttl: Option<Duration>,
+        #[cfg_attr(feature = "serde", serde(skip, default = "partial_eq"))]
+        eq: fn(&V, &V) -> bool,
+    },
+    /// combine the operand with the current value by the merge operator of the tree
+    Merge(V),
+    /// the actions on the same key in the order they were added,
+    /// made when a conditional action follows another action, never nested
+    Sequence(Vec<Action<V>>),
 }
+
+/// the eq of a deserialized compare and set action
+#[cfg(feature = "serde")]
+fn partial_eq<V: PartialEq>() -> fn(&V, &V) -> bool {
+    V::eq
+}
+
+/// Combine an operand with the existing value of a key,
+/// so counters and lists can be updated by deltas without reading them first
+///
+/// # Examples
+/// ```rust
+/// use mem_btree::{BTree, BatchWrite};
+/// let mut btree = BTree::new(32)
+///     .with_merge_operator(|_k: &&str, v: Option<&i64>, op: i64| v.unwrap_or(&0) + op);
+/// btree.merge("a", 1);
+/// let mut bw = BatchWrite::default();
+/// bw.merge("a", 2);
+/// bw.merge("a", 3);
+/// bw.merge("b", 4);
+/// btree.write(bw);
+/// assert_eq!(btree.get(&"a"), Some(&6));
+/// assert_eq!(btree.get(&"b"), Some(&4));
+/// ```
+pub trait MergeOperator<K, V>: Send + Sync {
+    /// return the new value of `key` from its existing value and the operand
+    fn merge(&self, key: &K, existing: Option<&V>, operand: V) -> V;
+}
+
+impl<K, V, F> MergeOperator<K, V> for F
+where
+    F: Fn(&K, Option<&V>, V) -> V + Send + Sync,
+{
+    fn merge(&self, key: &K, existing: Option<&V>, operand: V) -> V {
+        self(key, existing, operand)
+    }
+}
+
+/// The point actions of a batch being written, sorted by the comparator of the tree
+pub type Actions<K, V> = Vec<(K, Action<V>)>;
+
+/// What the actions need besides themselves while a batch is applied
+pub struct WriteContext<'a, K, V, C> {
+    pub ranges: &'a SortedRanges<K, C>,
+    pub merge_operator: Option<&'a dyn MergeOperator<K, V>>,
+}
+
+/// The value of a key while the actions on it are applied
+pub(crate) enum Current<K, V> {
+    Absent,
+    Old(Item<K, V>),
+    New(V, Option<Duration>),
+}
+
+impl<K, V> Current<K, V> {
+    fn value(&self) -> Option<&V> {
+        match self {
+            Current::Absent => None,
+            Current::Old(item) => Some(&item.1),
+            Current::New(v, _) => Some(v),
+        }
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        match self {
+            Current::Absent => None,
+            Current::Old(item) => item.2,
+            Current::New(_, ttl) => *ttl,
+        }
+    }
+}
+
 impl<V> Action<V> {
-    pub(crate) fn value(self) -> (V, Option<Duration>) {
+    /// apply the action on the current value of the key,
+    /// return false if a condition of the action did not hold
+    ///
+    /// # Panics
+    /// a merge action panics if the tree has no merge operator,
+    /// the batches are checked by `BTree::check_write` before they are applied
+    pub(crate) fn apply<K>(
+        self,
+        key: &K,
+        current: &mut Current<K, V>,
+        merge_operator: Option<&dyn MergeOperator<K, V>>,
+    ) -> bool {
+        match self {
+            Action::Put(v, ttl) => *current = Current::New(v, ttl),
+            Action::Delete => *current = Current::Absent,
+            Action::PutIfAbsent(v, ttl) => {
+                if current.value().is_some() {
+                    return false;
+                }
+                *current = Current::New(v, ttl);
+            }
+            Action::CompareAndSet {
+                expected,
+                value,
+                ttl,
+                eq,
+            } => match current.value() {
+                Some(v) if eq(v, &expected) => *current = Current::New(value, ttl),
+                _ => return false,
+            },
+            Action::Merge(operand) => {
+                let merge_operator = merge_operator.expect("merge operator is not set");
+                let v = merge_operator.merge(key, current.value(), operand);
+                *current = Current::New(v, current.ttl());
+            }
+            Action::Sequence(actions) => {
+                return actions
+                    .into_iter()
+                    .fold(true, |held, a| a.apply(key, current, merge_operator) & held)
+            }
+        }
+        true
+    }
+
+    /// whether applying the action needs the merge operator of the tree
+    fn needs_merge_operator(&self) -> bool {
         match self {
-            Self::Put(v, t) => (v, t),
-            Self::Delete => unreachable!(),
+            Action::Merge(_) => true,
+            Action::Sequence(actions) => actions.iter().any(Action::needs_merge_operator),
+            _ => false,
+        }
+    }
+
+    /// the action doing self and then next, a sequence never holds another one
+    fn then(self, next: Action<V>) -> Action<V> {
+        if matches!(next, Action::Put(..) | Action::Delete) {
+            return next;
+        }
+        let mut actions = match self {
+            Action::Sequence(actions) => actions,
+            prev => vec![prev],
+        };
+        match next {
+            Action::Sequence(next) => actions.extend(next),
+            next => actions.push(next),
+        }
+        Action::Sequence(actions)
+    }
+}
+
+/// What a batch write did to one key
+#[derive(Debug)]
+pub enum WriteOutcome<K, V> {
+    /// the key was not in the tree, hold the new item
+    Inserted(Item<K, V>),
+    /// the key was in the tree, hold the replaced old item
+    Replaced(Item<K, V>),
+    /// the key was deleted by a delete or a deleted range, hold the old item
+    Deleted(Item<K, V>),
+    /// the key to delete was not in the tree
+    DeleteMiss(K),
+    /// a condition of the action did not hold, the key was left as it was
+    Rejected(K),
+}
+
+/// Why a batch was not written, the tree is left as it was
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteError {
+    /// the batch has merge actions and the tree has no merge operator
+    NoMergeOperator,
+    /// the group of `GroupCommit` holding the batch panicked, none of it was published
+    Panicked,
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteError::NoMergeOperator => f.write_str("merge operator is not set"),
+            WriteError::Panicked => f.write_str("group commit of the batch panicked"),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+/// move the entries of other into map, the smaller one is inserted into the larger one
+fn append<K: Ord, T>(map: &mut BTreeMap<K, T>, mut other: BTreeMap<K, T>) {
+    if other.len() > map.len() {
+        std::mem::swap(map, &mut other);
+    }
+    map.extend(other);
+}
+
+/// A key of a batch ordered by the comparator of the trees it is written to
+struct Ordered<K, C>(K, PhantomData<fn() -> C>);
+
+impl<K, C> Ordered<K, C> {
+    fn new(k: K) -> Self {
+        Self(k, PhantomData)
+    }
+}
+
+impl<K, C: Comparator<K>> Ord for Ordered<K, C> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        C::cmp(&self.0, &other.0)
+    }
+}
+
+impl<K, C: Comparator<K>> PartialOrd for Ordered<K, C> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K, C: Comparator<K>> PartialEq for Ordered<K, C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl<K, C: Comparator<K>> Eq for Ordered<K, C> {}
+
+impl<K: Debug, C> Debug for Ordered<K, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The key ranges deleted by a batch, keyed by the inclusive start key,
+/// the value is the exclusive end key. ranges never overlap
+struct DeleteRanges<K, C> {
+    inner: BTreeMap<Ordered<K, C>, K>,
+}
+
+impl<K, C> DeleteRanges<K, C>
+where
+    C: Comparator<K>,
+{
+    /// add a range, and merge it with the ranges it overlaps
+    fn insert(&mut self, range: Range<K>) {
+        let mut start = Ordered::new(range.start);
+        let mut end = Ordered::new(range.end);
+
+        let overlap_before = match self.inner.range(..&start).next_back() {
+            Some((_, e)) => C::cmp(e, &start.0).is_ge(),
+            None => false,
+        };
+        if !overlap_before && self.inner.range(&start..=&end).next().is_none() {
+            self.inner.insert(start, end.0);
+            return;
+        }
+
+        let mut after = self.inner.split_off(&start);
+
+        if let Some(last) = self.inner.last_entry() {
+            if C::cmp(last.get(), &start.0).is_ge() {
+                let (s, e) = last.remove_entry();
+                start = s;
+                if C::cmp(&e, &end.0).is_gt() {
+                    end = Ordered::new(e);
+                }
+            }
+        }
+
+        while let Some(first) = after.first_entry() {
+            if *first.key() > end {
+                break;
+            }
+            let (_, e) = first.remove_entry();
+            if C::cmp(&e, &end.0).is_gt() {
+                end = Ordered::new(e);
+            }
+        }
+
+        append(&mut self.inner, after);
+        self.inner.insert(start, end.0);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+impl<K: Debug, C> Debug for DeleteRanges<K, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.inner.iter()).finish()
+    }
+}
+
+/// The deleted ranges of a batch while it is written, in the order of C
+pub struct SortedRanges<K, C> {
+    inner: Vec<(K, K)>,
+    comparator: PhantomData<fn() -> C>,
+}
+
+impl<K, C> SortedRanges<K, C>
+where
+    C: Comparator<K>,
+{
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// whether k is in one of the ranges
+    pub fn contains(&self, k: &K) -> bool {
+        self.covers(k, k)
+    }
+
+    /// whether one range contains all keys from start to end
+    pub fn covers(&self, start: &K, end: &K) -> bool {
+        let i = self
+            .inner
+            .partition_point(|(s, _)| C::cmp(s, start).is_le());
+        i > 0 && C::cmp(end, &self.inner[i - 1].1).is_lt()
+    }
+
+    /// whether some keys from start to end are in the ranges
+    pub fn intersects(&self, start: &K, end: &K) -> bool {
+        if self.contains(start) {
+            return true;
+        }
+        // the first range starting after start
+        let i = self
+            .inner
+            .partition_point(|(s, _)| C::cmp(s, start).is_le());
+        matches!(self.inner.get(i), Some((s, _)) if C::cmp(s, end).is_le())
+    }
+
+    /// the ranges as start and exclusive end keys, in key order
+    pub(crate) fn into_inner(self) -> Vec<(K, K)> {
+        self.inner
+    }
+}
+
+/// A batch of actions, applied at once by `BTree::write`.
+/// The keys are ordered by the comparator C of the trees it is written to
+pub struct BatchWrite<K, V, C = OrdComparator> {
+    /// the point actions, `None` for the actions dropped by a later deleted range
+    inner: BTreeMap<Ordered<K, C>, Option<Action<V>>>,
+    /// the number of dropped actions still in inner
+    dropped: usize,
+    ranges: DeleteRanges<K, C>,
+}
+
+impl<K, V, C> Default for BatchWrite<K, V, C> {
+    fn default() -> Self {
+        Self {
+            inner: BTreeMap::new(),
+            dropped: 0,
+            ranges: DeleteRanges {
+                inner: BTreeMap::new(),
+            },
         }
     }
 }
 
-#[derive(Debug, Default)]
-pub struct BatchWrite<K, V> {
-    inner: BTreeMap<K, Action<V>>,
+impl<K: Debug, V: Debug, C> Debug for BatchWrite<K, V, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BatchWrite")
+            .field(
+                "inner",
+                &self
+                    .inner
+                    .iter()
+                    .filter_map(|(k, a)| Some((k, a.as_ref()?)))
+                    .collect::<Vec<_>>(),
+            )
+            .field("ranges", &self.ranges)
+            .finish()
+    }
 }
 
-impl<K, V> BatchWrite<K, V>
+impl<K, V, C> BatchWrite<K, V, C>
 where
-    K: Ord,
+    C: Comparator<K>,
 {
     pub fn put(&mut self, key: K, value: V) {
-        self.inner.insert(key, Action::Put(value, None));
+        self.push(key, Action::Put(value, None));
     }
 
     pub fn put_ttl(&mut self, key: K, value: V, ttl: Duration) {
-        self.inner
-            .insert(key, Action::Put(value, Some(now().add(ttl))));
+        self.push(key, Action::Put(value, Some(now().add(ttl))));
     }
 
+    /// remove the key, a tree of `Value` hides it with `tombstone` instead
     pub fn delete(&mut self, key: K) {
-        self.inner.insert(key, Action::Delete);
+        self.push(key, Action::Delete);
+    }
+
+    /// put the value only if the key is absent when the batch is applied
+    pub fn put_if_absent(&mut self, key: K, value: V) {
+        self.push(key, Action::PutIfAbsent(value, None));
+    }
+
+    /// put the value only if the value of the key equals `expected` when the batch is applied
+    pub fn compare_and_set(&mut self, key: K, expected: V, value: V)
+    where
+        V: PartialEq,
+    {
+        self.push(
+            key,
+            Action::CompareAndSet {
+                expected,
+                value,
+                ttl: None,
+                eq: V::eq,
+            },
+        );
+    }
+
+    /// combine the operand with the value of the key when the batch is applied,
+    /// by the merge operator of the tree
+    pub fn merge(&mut self, key: K, operand: V) {
+        self.push(key, Action::Merge(operand));
+    }
+
+    /// add an action after the actions already on the key
+    pub(crate) fn push(&mut self, key: K, action: Action<V>) {
+        match self.inner.entry(Ordered::new(key)) {
+            Entry::Vacant(e) => {
+                e.insert(Some(action));
+            }
+            Entry::Occupied(mut e) => {
+                let slot = e.get_mut();
+                *slot = Some(match slot.take() {
+                    Some(prev) => prev.then(action),
+                    None => {
+                        self.dropped -= 1;
+                        action
+                    }
+                });
+            }
+        }
     }
 
-    pub fn into_map(self) -> BTreeMap<K, Action<V>> {
+    /// delete all keys in `start..end`
+    /// the actions added before in the range are dropped,
+    /// the actions added after it are applied over the deleted range,
+    /// the older versions in a `MergedIter` show again for the removed keys
+    pub fn delete_range(&mut self, range: Range<K>) {
+        if C::cmp(&range.start, &range.end).is_ge() {
+            return;
+        }
+
+        let start = Ordered::new(range.start);
+        let end = Ordered::new(range.end);
+        // the actions in the range are dropped in place, cutting the map
+        // would cost as much as the actions outside the range
+        for (_, action) in self.inner.range_mut(&start..&end) {
+            if action.take().is_some() {
+                self.dropped += 1;
+            }
+        }
+        if self.dropped > self.inner.len() - self.dropped {
+            self.inner.retain(|_, action| action.is_some());
+            self.dropped = 0;
+        }
+
+        self.ranges.insert(start.0..end.0);
+    }
+
+    /// add the actions of other after the actions of self,
+    /// applying the result is the same as applying self and then other
+    pub fn append(&mut self, other: BatchWrite<K, V, C>) {
+        // the point actions of other were all added after its ranges
+        for (start, end) in other.ranges.inner {
+            self.delete_range(start.0..end);
+        }
+        for (k, action) in other.inner {
+            if let Some(action) = action {
+                self.push(k.0, action);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len() - self.dropped + self.ranges.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.len() == self.dropped && self.ranges.is_empty()
+    }
+
+    /// the point actions of this batch keyed by `Ord`, the deleted ranges are not included
+    pub fn into_map(self) -> BTreeMap<K, Action<V>>
+    where
+        K: Ord,
+    {
         self.inner
+            .into_iter()
+            .filter_map(|(k, a)| Some((k.0, a?)))
+            .collect()
+    }
+
+    /// whether some action of this batch needs the merge operator of the tree
+    pub(crate) fn needs_merge_operator(&self) -> bool {
+        self.inner
+            .values()
+            .flatten()
+            .any(Action::needs_merge_operator)
+    }
+
+    /// the deleted ranges as start and exclusive end keys, in key order
+    pub(crate) fn ranges(&self) -> impl ExactSizeIterator<Item = (&K, &K)> {
+        self.ranges.inner.iter().map(|(s, e)| (&s.0, e))
+    }
+
+    /// the point actions in key order
+    pub(crate) fn actions(&self) -> impl Iterator<Item = (&K, &Action<V>)> {
+        self.inner
+            .iter()
+            .filter_map(|(k, a)| Some((&k.0, a.as_ref()?)))
+    }
+
+    /// the point actions and the deleted ranges, both sorted by C
+    pub(crate) fn into_parts(self) -> (Actions<K, V>, SortedRanges<K, C>) {
+        let actions = self
+            .inner
+            .into_iter()
+            .filter_map(|(k, a)| Some((k.0, a?)))
+            .collect();
+        let ranges = SortedRanges {
+            inner: self
+                .ranges
+                .inner
+                .into_iter()
+                .map(|(s, e)| (s.0, e))
+                .collect(),
+            comparator: PhantomData,
+        };
+        (actions, ranges)
     }
 }
@@ -0,0 +1,450 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    sync::Arc,
+};
+
+use crate::{codec, leaf::Leaf, node::Node, BTree, BTreeType, Codec, Item, N};
+
+/// the first bytes of a checkpoint
+const MAGIC: &[u8; 4] = b"MBCP";
+
+/// the version of the format written by `BTree::checkpoint`
+const VERSION: u8 = 1;
+
+/// a record holds a leaf
+const LEAF: u8 = 0;
+
+/// a record holds the content ids of the children of a node
+const NODE: u8 = 1;
+
+/// the 128 bits FNV-1a hash of the encoded content of a node, it is not
+/// collision resistant, a known id is only trusted for the same content
+fn content_id(data: &[u8]) -> u128 {
+    const OFFSET: u128 = 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d;
+    const PRIME: u128 = 0x0000_0000_0100_0000_0000_0000_0000_013b;
+    data.iter()
+        .fold(OFFSET, |h, b| (h ^ *b as u128).wrapping_mul(PRIME))
+}
+
+fn addr<K, V>(node: &N<K, V>) -> usize {
+    Arc::as_ptr(node) as *const () as usize
+}
+
+/// The nodes persisted by the checkpoints up to one tree.
+///
+/// Every node of the checkpointed tree is known by its address and its content id.
+/// The nodes are retained, so an address is not reused by another node while
+/// the next checkpoint looks it up. A node the next tree still shares is
+/// neither encoded nor written again, a new node with a known content is not
+/// written again. The content of the known node is compared byte by byte
+/// first, two contents with the same id fail the checkpoint.
+///
+/// # Examples
+/// ```rust
+/// use mem_btree::{BTree, Checkpoint};
+///
+/// let mut btree = BTree::new(32);
+/// for i in 0..10000u32 {
+///     btree.put(i, i);
+/// }
+/// let mut full = Vec::new();
+/// let checkpoint = btree.checkpoint(&Checkpoint::default(), &mut full).unwrap();
+///
+/// btree.put(5000, 0);
+/// let mut delta = Vec::new();
+/// btree.checkpoint(&checkpoint, &mut delta).unwrap();
+/// assert!(delta.len() * 20 < full.len());
+///
+/// let (loaded, _) = Checkpoint::<u32, u32>::load([&full[..], &delta[..]]).unwrap();
+/// assert_eq!(loaded.get(&5000), Some(&0));
+/// ```
+pub struct Checkpoint<K, V> {
+    /// the content id of every node of the tree, by address
+    nodes: HashMap<usize, (u128, N<K, V>)>,
+    /// a node of the tree for every content id
+    ids: HashMap<u128, N<K, V>>,
+}
+
+impl<K, V> Default for Checkpoint<K, V> {
+    /// a checkpoint of nothing, the next checkpoint writes all nodes
+    fn default() -> Self {
+        Self {
+            nodes: HashMap::new(),
+            ids: HashMap::new(),
+        }
+    }
+}
+
+impl<K, V> Checkpoint<K, V>
+where
+    K: Ord + Codec,
+    V: Codec,
+{
+    fn insert(&mut self, node: &N<K, V>, id: u128) {
+        self.nodes.insert(addr(node), (id, node.clone()));
+        self.ids.entry(id).or_insert_with(|| node.clone());
+    }
+
+    /// the number of nodes in the checkpointed tree
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// write the nodes of the tree not in prev, children before their parent
+    pub(crate) fn write<W: Write>(
+        tree: &BTree<K, V>,
+        prev: &Checkpoint<K, V>,
+        mut w: W,
+    ) -> io::Result<Self> {
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION])?;
+        tree.m.encode(&mut w)?;
+
+        let mut next = Checkpoint::default();
+        let root = next.visit(&tree.root, prev, &mut w)?;
+
+        false.encode(&mut w)?;
+        root.encode(&mut w)?;
+        w.flush()?;
+
+        Ok(next)
+    }
+
+    fn visit<W: Write>(
+        &mut self,
+        node: &N<K, V>,
+        prev: &Checkpoint<K, V>,
+        w: &mut W,
+    ) -> io::Result<u128> {
+        if let Some((id, _)) = prev.nodes.get(&addr(node)) {
+            self.carry(node, prev);
+            return Ok(*id);
+        }
+        if let Some((id, _)) = self.nodes.get(&addr(node)) {
+            return Ok(*id);
+        }
+
+        let content = Self::content(node, |child| self.visit(child, prev, w))?;
+        let id = content_id(&content);
+        let known = match (prev.ids.get(&id), self.ids.get(&id)) {
+            (Some(n), _) => Some(prev.known_content(n)?),
+            (None, Some(n)) => Some(self.known_content(n)?),
+            (None, None) => None,
+        };
+        match known {
+            None => {
+                true.encode(w)?;
+                id.encode(w)?;
+                w.write_all(&content)?;
+            }
+            Some(known) if known == content => {}
+            Some(_) => return Err(io::Error::other("two nodes have the same content id")),
+        }
+        self.insert(node, id);
+        Ok(id)
+    }
+
+    /// the encoded content of a node, child_id gives the content ids of its children
+    fn content<F>(node: &N<K, V>, mut child_id: F) -> io::Result<Vec<u8>>
+    where
+        F: FnMut(&N<K, V>) -> io::Result<u128>,
+    {
+        let mut content = Vec::new();
+        match &**node {
+            BTreeType::Leaf(l) => {
+                LEAF.encode(&mut content)?;
+                l.items.len().encode(&mut content)?;
+                for item in l.items.iter() {
+                    item.2.encode(&mut content)?;
+                    item.0.encode(&mut content)?;
+                    item.1.encode(&mut content)?;
+                }
+            }
+            BTreeType::Node(n) => {
+                NODE.encode(&mut content)?;
+                n.children.len().encode(&mut content)?;
+                for child in n.children.iter() {
+                    child_id(child)?.encode(&mut content)?;
+                }
+            }
+        }
+        Ok(content)
+    }
+
+    /// the content of a node of this checkpoint, its children are in it too
+    fn known_content(&self, node: &N<K, V>) -> io::Result<Vec<u8>> {
+        Self::content(node, |child| Ok(self.nodes[&addr(child)].0))
+    }
+
+    /// take the ids of a subtree shared with prev, nothing of it is written
+    fn carry(&mut self, node: &N<K, V>, prev: &Checkpoint<K, V>) {
+        if self.nodes.contains_key(&addr(node)) {
+            return;
+        }
+        let (id, _) = prev.nodes[&addr(node)];
+        self.insert(node, id);
+        if let BTreeType::Node(n) = &**node {
+            for child in n.children.iter() {
+                self.carry(child, prev);
+            }
+        }
+    }
+
+    /// Load the tree of the last of a chain of checkpoints, the first one
+    /// written from `Checkpoint::default()` and every next one from the one before.
+    /// The nodes shared by the checkpoints are shared by the loaded trees,
+    /// the returned checkpoint continues the chain
+    pub fn load<R, I>(checkpoints: I) -> io::Result<(BTree<K, V>, Self)>
+    where
+        R: Read,
+        I: IntoIterator<Item = R>,
+    {
+        let mut store: HashMap<u128, N<K, V>> = HashMap::new();
+        let mut ids: HashMap<usize, u128> = HashMap::new();
+        let mut last = None;
+
+        for mut r in checkpoints {
+            let mut magic = [0; 4];
+            r.read_exact(&mut magic)?;
+            if &magic != MAGIC {
+                return Err(codec::invalid("not a checkpoint"));
+            }
+            let version = u8::decode(&mut r)?;
+            if version != VERSION {
+                return Err(codec::invalid(format!("unknown version {version}")));
+            }
+            let m = usize::decode(&mut r)?;
+            if m < 2 {
+                return Err(codec::invalid(format!("bad m {m}")));
+            }
+
+            while bool::decode(&mut r)? {
+                let id = u128::decode(&mut r)?;
+                let mut content = Recorder {
+                    inner: &mut r,
+                    data: Vec::new(),
+                };
+                let node = Self::read_node(&mut content, &store)?;
+                if content_id(&content.data) != id {
+                    return Err(codec::invalid("content id of a node does not match"));
+                }
+                ids.insert(addr(&node), id);
+                store.insert(id, node);
+            }
+
+            let root = u128::decode(&mut r)?;
+            let root = store
+                .get(&root)
+                .cloned()
+                .ok_or_else(|| codec::invalid("root is not in the checkpoints"))?;
+            last = Some((m, root));
+        }
+
+        let (m, root) = last.ok_or_else(|| codec::invalid("no checkpoint"))?;
+        let mut checkpoint = Checkpoint::default();
+        checkpoint.collect(&root, &ids);
+
+        let mut tree = BTree::new(m);
+        tree.root = root;
+        Ok((tree, checkpoint))
+    }
+
+    fn read_node<R: Read>(r: &mut R, store: &HashMap<u128, N<K, V>>) -> io::Result<N<K, V>> {
+        match u8::decode(r)? {
+            LEAF => {
+                let len = usize::decode(r)?;
+                let mut items: Vec<Item<K, V>> = Vec::with_capacity(len.min(1024));
+                for _ in 0..len {
+                    let ttl = Option::decode(r)?;
+                    let k = K::decode(r)?;
+                    let v = V::decode(r)?;
+                    items.push(Arc::new((k, v, ttl)));
+                }
+                Ok(Leaf::instance(items))
+            }
+            NODE => {
+                let len = usize::decode(r)?;
+                let mut children = Vec::with_capacity(len.min(1024));
+                for _ in 0..len {
+                    let id = u128::decode(r)?;
+                    let child = store
+                        .get(&id)
+                        .ok_or_else(|| codec::invalid("child is not in the checkpoints"))?;
+                    children.push(child.clone());
+                }
+                Ok(Node::instance(children))
+            }
+            kind => Err(codec::invalid(format!("bad node kind {kind}"))),
+        }
+    }
+
+    fn collect(&mut self, node: &N<K, V>, ids: &HashMap<usize, u128>) {
+        if self.nodes.contains_key(&addr(node)) {
+            return;
+        }
+        self.insert(node, ids[&addr(node)]);
+        if let BTreeType::Node(n) = &**node {
+            for child in n.children.iter() {
+                self.collect(child, ids);
+            }
+        }
+    }
+}
+
+/// keep the bytes read through it, to check the content id of a node
+struct Recorder<'a, R> {
+    inner: &'a mut R,
+    data: Vec<u8>,
+}
+
+impl<R: Read> Read for Recorder<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.data.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{content_id, Checkpoint};
+    use crate::BTree;
+
+    fn items(tree: &BTree<u32, String>) -> Vec<(u32, String, Option<Duration>)> {
+        tree.iter().map(|i| (i.0, i.1.clone(), i.2)).collect()
+    }
+
+    #[test]
+    fn test_incremental_checkpoint() {
+        let mut rng = StdRng::seed_from_u64(42);
+
+        let mut btree = BTree::new(16);
+        for _ in 0..20000 {
+            let k = rng.gen::<u32>();
+            btree.put(k, format!("v{k}"));
+        }
+        btree.put_ttl(1, String::from("ttl"), Duration::from_secs(100));
+
+        let mut files = vec![Vec::new()];
+        let mut snapshots = vec![btree.clone()];
+        let mut checkpoint = btree
+            .checkpoint(&Checkpoint::default(), &mut files[0])
+            .unwrap();
+
+        for round in 0..5 {
+            for _ in 0..20 {
+                let k = rng.gen::<u32>();
+                if round % 2 == 0 {
+                    btree.put(k, String::new());
+                } else {
+                    let k = btree.iter().nth(rng.gen_range(0..1000)).unwrap().0;
+                    btree.remove(&k);
+                }
+            }
+            let mut file = Vec::new();
+            checkpoint = btree.checkpoint(&checkpoint, &mut file).unwrap();
+            assert!(file.len() * 10 < files[0].len());
+            files.push(file);
+            snapshots.push(btree.clone());
+        }
+
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            let (loaded, _) =
+                Checkpoint::<u32, String>::load(files[..=i].iter().map(|f| f.as_slice())).unwrap();
+            assert_eq!(loaded.m, 16);
+            assert_eq!(items(&loaded), items(snapshot));
+        }
+
+        // the loaded checkpoint continues the chain
+        let (mut loaded, checkpoint) =
+            Checkpoint::<u32, String>::load(files.iter().map(|f| f.as_slice())).unwrap();
+        assert_eq!(checkpoint.len(), {
+            let mut full = Vec::new();
+            btree
+                .checkpoint(&Checkpoint::default(), &mut full)
+                .unwrap()
+                .len()
+        });
+        loaded.put(7, String::from("after load"));
+        let mut file = Vec::new();
+        loaded.checkpoint(&checkpoint, &mut file).unwrap();
+        assert!(file.len() * 10 < files[0].len());
+        files.push(file);
+        let (reloaded, _) =
+            Checkpoint::<u32, String>::load(files.iter().map(|f| f.as_slice())).unwrap();
+        assert_eq!(items(&reloaded), items(&loaded));
+
+        // nothing changed, no node is written
+        let mut file = Vec::new();
+        let same = loaded.checkpoint(&checkpoint, &mut file).unwrap();
+        let mut again = Vec::new();
+        loaded.checkpoint(&same, &mut again).unwrap();
+        assert_eq!(again.len(), 4 + 1 + 1 + 1 + 16);
+    }
+
+    #[test]
+    fn test_checkpoint_bad_data() {
+        let mut btree = BTree::new(4);
+        for i in 0..100u32 {
+            btree.put(i, format!("v{i}"));
+        }
+        let mut full = Vec::new();
+        let checkpoint = btree.checkpoint(&Checkpoint::default(), &mut full).unwrap();
+        btree.put(1000, String::new());
+        let mut delta = Vec::new();
+        btree.checkpoint(&checkpoint, &mut delta).unwrap();
+
+        // a delta alone misses the shared nodes
+        assert!(Checkpoint::<u32, String>::load([&delta[..]]).is_err());
+
+        let mut bad = full.clone();
+        let i = bad.len() / 2;
+        bad[i] ^= 0xff;
+        assert!(Checkpoint::<u32, String>::load([&bad[..]]).is_err());
+
+        assert!(Checkpoint::<u32, String>::load([&full[..full.len() - 1]]).is_err());
+
+        // m follows the magic and the version
+        let mut bad = full.clone();
+        assert_eq!(bad[5], 4);
+        bad[5] = 1;
+        let err = Checkpoint::<u32, String>::load([&bad[..]]).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_checkpoint_known_content() {
+        let build = |n: u32| {
+            let mut btree = BTree::new(8);
+            for i in 0..n {
+                btree.put(i, format!("v{i}"));
+            }
+            btree
+        };
+        let mut full = Vec::new();
+        let checkpoint = build(1000)
+            .checkpoint(&Checkpoint::default(), &mut full)
+            .unwrap();
+
+        // a tree built again shares no node but has the same contents
+        let mut again = Vec::new();
+        build(1000).checkpoint(&checkpoint, &mut again).unwrap();
+        assert_eq!(again.len(), 4 + 1 + 1 + 1 + 16);
+
+        // another node known under the id of the root is a collision
+        let btree = build(3);
+        let id = content_id(&Checkpoint::content(&btree.root, |_| unreachable!()).unwrap());
+        let mut prev = Checkpoint::default();
+        prev.insert(&build(2).root, id);
+        assert!(btree.checkpoint(&prev, &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use crate::{Action, BatchWrite};
+
+/// the first bytes of a serialized tree
+pub(crate) const MAGIC: &[u8; 4] = b"MBTR";
+
+/// the version of the format written by `BTree::write_to`
+pub(crate) const VERSION: u8 = 1;
+
+/// Encode and decode keys and values for `BTree::write_to` and `BTree::read_from`
+///
+/// # Examples
+/// ```rust
+/// use std::io::{self, Read, Write};
+/// use mem_btree::Codec;
+///
+/// struct Point(i32, i32);
+///
+/// impl Codec for Point {
+///     fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+///         self.0.encode(w)?;
+///         self.1.encode(w)
+///     }
+///
+///     fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+///         Ok(Point(i32::decode(r)?, i32::decode(r)?))
+///     }
+/// }
+/// ```
+pub trait Codec: Sized {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()>;
+
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self>;
+}
+
+macro_rules! codec_number {
+    ($($t:ty),*) => {
+        $(
+            impl Codec for $t {
+                fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+                    w.write_all(&self.to_le_bytes())
+                }
+
+                fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+                    let mut buf = [0; std::mem::size_of::<$t>()];
+                    r.read_exact(&mut buf)?;
+                    Ok(<$t>::from_le_bytes(buf))
+                }
+            }
+        )*
+    };
+}
+
+codec_number!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+impl Codec for usize {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_varint(w, *self as u64)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        usize::try_from(read_varint(r)?).map_err(invalid)
+    }
+}
+
+impl Codec for bool {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (*self as u8).encode(w)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        match u8::decode(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(invalid(format!("bad bool {b}"))),
+        }
+    }
+}
+
+impl Codec for () {
+    fn encode<W: Write>(&self, _w: &mut W) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn decode<R: Read>(_r: &mut R) -> io::Result<Self> {
+        Ok(())
+    }
+}
+
+impl Codec for String {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_varint(w, self.len() as u64)?;
+        w.write_all(self.as_bytes())
+    }
+
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        String::from_utf8(read_bytes(r)?).map_err(invalid)
+    }
+}
+
+impl<T: Codec> Codec for Vec<T> {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_varint(w, self.len() as u64)?;
+        self.iter().try_for_each(|v| v.encode(w))
+    }
+
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        let len = read_varint(r)?;
+        // the length is not trusted for the capacity, the data may be corrupted
+        let mut values = Vec::with_capacity(len.min(1024) as usize);
+        for _ in 0..len {
+            values.push(T::decode(r)?);
+        }
+        Ok(values)
+    }
+}
+
+impl<T: Codec> Codec for Option<T> {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            Some(v) => {
+                true.encode(w)?;
+                v.encode(w)
+            }
+            None => false.encode(w),
+        }
+    }
+
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        if bool::decode(r)? {
+            Ok(Some(T::decode(r)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Codec for Duration {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.as_secs().encode(w)?;
+        self.subsec_nanos().encode(w)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        let secs = u64::decode(r)?;
+        let nanos = u32::decode(r)?;
+        if nanos >= 1_000_000_000 {
+            return Err(invalid(format!("bad nanos {nanos}")));
+        }
+        Ok(Duration::new(secs, nanos))
+    }
+}
+
+impl<A: Codec, B: Codec> Codec for (A, B) {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.0.encode(w)?;
+        self.1.encode(w)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok((A::decode(r)?, B::decode(r)?))
+    }
+}
+
+impl<V: Codec + PartialEq> Codec for Action<V> {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            Action::Put(v, ttl) => {
+                0u8.encode(w)?;
+                v.encode(w)?;
+                ttl.encode(w)
+            }
+            Action::Delete => 1u8.encode(w),
+            Action::PutIfAbsent(v, ttl) => {
+                2u8.encode(w)?;
+                v.encode(w)?;
+                ttl.encode(w)
+            }
+            Action::CompareAndSet {
+                expected,
+                value,
+                ttl,
+                ..
+            } => {
+                3u8.encode(w)?;
+                expected.encode(w)?;
+                value.encode(w)?;
+                ttl.encode(w)
+            }
+            Action::Merge(v) => {
+                4u8.encode(w)?;
+                v.encode(w)
+            }
+            Action::Sequence(actions) => {
+                5u8.encode(w)?;
+                actions.encode(w)
+            }
+        }
+    }
+
+    /// a compare and set action compares by `PartialEq` after decoding
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        decode_action(r, false)
+    }
+}
+
+/// decode an action, a sequence inside a sequence is `InvalidData`
+/// so corrupted data cannot nest them without bound
+fn decode_action<V, R>(r: &mut R, in_sequence: bool) -> io::Result<Action<V>>
+where
+    V: Codec + PartialEq,
+    R: Read,
+{
+    Ok(match u8::decode(r)? {
+        0 => Action::Put(V::decode(r)?, Option::decode(r)?),
+        1 => Action::Delete,
+        2 => Action::PutIfAbsent(V::decode(r)?, Option::decode(r)?),
+        3 => Action::CompareAndSet {
+            expected: V::decode(r)?,
+            value: V::decode(r)?,
+            ttl: Option::decode(r)?,
+            eq: V::eq,
+        },
+        4 => Action::Merge(V::decode(r)?),
+        5 if in_sequence => return Err(invalid("nested action sequence")),
+        5 => {
+            let len = read_varint(r)?;
+            let mut actions = Vec::with_capacity(len.min(1024) as usize);
+            for _ in 0..len {
+                actions.push(decode_action(r, true)?);
+            }
+            Action::Sequence(actions)
+        }
+        tag => return Err(invalid(format!("bad action {tag}"))),
+    })
+}
+
+/// the deleted ranges and then the point actions,
+/// a decoded batch is applied the same as the encoded one
+impl<K: Codec + Ord, V: Codec + PartialEq> Codec for BatchWrite<K, V> {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let ranges = self.ranges();
+        ranges.len().encode(w)?;
+        for (start, end) in ranges {
+            start.encode(w)?;
+            end.encode(w)?;
+        }
+
+        let actions: Vec<_> = self.actions().collect();
+        actions.len().encode(w)?;
+        for (k, action) in actions {
+            k.encode(w)?;
+            action.encode(w)?;
+        }
+        Ok(())
+    }
+
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut batch_write = BatchWrite::default();
+        // the ranges first, they override the point actions added before them
+        for _ in 0..usize::decode(r)? {
+            let start = K::decode(r)?;
+            let end = K::decode(r)?;
+            batch_write.delete_range(start..end);
+        }
+        for _ in 0..usize::decode(r)? {
+            let k = K::decode(r)?;
+            let action = Action::decode(r)?;
+            batch_write.push(k, action);
+        }
+        Ok(batch_write)
+    }
+}
+
+/// the crc32 (IEEE) table, computed at compile time
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |c, b| {
+        CRC_TABLE[((c ^ *b as u32) & 0xff) as usize] ^ (c >> 8)
+    })
+}
+
+/// write an unsigned integer in 7 bits groups, small numbers take one byte
+pub(crate) fn write_varint<W: Write>(w: &mut W, mut v: u64) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let b = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            buf[len] = b;
+            len += 1;
+            break;
+        }
+        buf[len] = b | 0x80;
+        len += 1;
+    }
+    w.write_all(&buf[..len])
+}
+
+pub(crate) fn read_varint<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let b = u8::decode(r)?;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(invalid("varint is too long"))
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_varint(r)?;
+    let mut buf = Vec::with_capacity(len.min(1 << 16) as usize);
+    r.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+pub(crate) fn invalid<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fmt::Debug, io, time::Duration};
+
+    use super::{crc32, read_varint, write_varint, Codec};
+    use crate::Action;
+
+    fn round_trip<T: Codec + PartialEq + Debug>(v: T) {
+        let mut buf = Vec::new();
+        v.encode(&mut buf).unwrap();
+        let mut r = buf.as_slice();
+        assert_eq!(T::decode(&mut r).unwrap(), v);
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn test_codec_round_trip() {
+        round_trip(0u8);
+        round_trip(-7i32);
+        round_trip(u64::MAX);
+        round_trip(i128::MIN);
+        round_trip(1.5f64);
+        round_trip(usize::MAX);
+        round_trip(true);
+        round_trip(());
+        round_trip(String::from("btree"));
+        round_trip(vec![String::new(), String::from("a")]);
+        round_trip(Some(vec![1u16, 2, 3]));
+        round_trip(None::<u8>);
+        round_trip(Duration::new(3, 999_999_999));
+        round_trip((1u8, String::from("b")));
+
+        for v in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, v).unwrap();
+            assert_eq!(read_varint(&mut buf.as_slice()).unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_codec_bad_data() {
+        let err = |buf: &[u8]| String::decode(&mut &buf[..]).unwrap_err().kind();
+        assert_eq!(err(&[3, b'a']), io::ErrorKind::UnexpectedEof);
+        assert_eq!(err(&[1, 0xff]), io::ErrorKind::InvalidData);
+        assert_eq!(err(&[0xff; 11]), io::ErrorKind::InvalidData);
+        assert_eq!(
+            bool::decode(&mut &[2u8][..]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // a sequence of a delete and a merge, and one nested in another
+        let action = Action::<u8>::decode(&mut &[5u8, 2, 1, 4, 7][..]).unwrap();
+        assert!(matches!(action, Action::Sequence(a) if a.len() == 2));
+        assert_eq!(
+            Action::<u8>::decode(&mut &[5u8, 1, 5, 0][..])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
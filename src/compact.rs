@@ -0,0 +1,598 @@
+use crate::{
+    batch_write::{Actions, WriteContext, WriteOutcome},
+    *,
+};
+
+/// The items of one leaf of a `CompactBTree`, the prefix shared by all
+/// their keys is stored once and every entry keeps only its suffix
+struct Block<V> {
+    prefix: Box<[u8]>,
+    /// the suffixes of the keys with their values and ttl deadlines, in key order
+    entries: Vec<(Box<[u8]>, V, Option<Duration>)>,
+}
+
+impl<V: Clone> Block<V> {
+    /// compress sorted items, the prefix shared by all of them is the one
+    /// shared by the first and the last key
+    fn new(items: &[Item<Vec<u8>, V>]) -> Self {
+        let first = &items[0].0;
+        let last = &items[items.len() - 1].0;
+        let n = first.iter().zip(last).take_while(|(a, b)| a == b).count();
+        Self {
+            prefix: first[..n].into(),
+            entries: items
+                .iter()
+                .map(|i| (i.0[n..].into(), i.1.clone(), i.2))
+                .collect(),
+        }
+    }
+
+    /// cut sorted items into the fewest blocks of at most m items, the
+    /// blocks are even so that an overfull block splits in halves
+    /// like a leaf of `BTree` and no block is left with a few items
+    fn split(m: usize, items: &[Item<Vec<u8>, V>]) -> impl Iterator<Item = Self> + '_ {
+        let n = items.len().div_ceil(m);
+        (0..n).map(move |i| Self::new(&items[i * items.len() / n..(i + 1) * items.len() / n]))
+    }
+
+    fn key(&self, index: usize) -> Vec<u8> {
+        let suffix = &self.entries[index].0;
+        let mut key = Vec::with_capacity(self.prefix.len() + suffix.len());
+        key.extend_from_slice(&self.prefix);
+        key.extend_from_slice(suffix);
+        key
+    }
+
+    /// rebuild the item at the index
+    fn item(&self, index: usize) -> Item<Vec<u8>, V> {
+        let e = &self.entries[index];
+        Arc::new((self.key(index), e.1.clone(), e.2))
+    }
+
+    fn items(&self) -> Vec<Item<Vec<u8>, V>> {
+        (0..self.entries.len()).map(|i| self.item(i)).collect()
+    }
+
+    /// the index of k, or the index it would be inserted at
+    fn search(&self, k: &[u8]) -> Result<usize, usize> {
+        match k.strip_prefix(&*self.prefix) {
+            Some(suffix) => self.entries.binary_search_by(|e| (*e.0).cmp(suffix)),
+            // every key of the block starts with the prefix
+            None if k < &*self.prefix => Err(0),
+            None => Err(self.entries.len()),
+        }
+    }
+}
+
+/// the number of items in the blocks of a subtree
+#[derive(Clone)]
+struct Entries(usize);
+
+impl<V> Aggregate<Vec<u8>, Arc<Block<V>>> for Entries {
+    fn identity() -> Self {
+        Entries(0)
+    }
+
+    fn from_item(_: &Vec<u8>, block: &Arc<Block<V>>) -> Self {
+        Entries(block.entries.len())
+    }
+
+    fn combine(&self, other: &Self) -> Self {
+        Entries(self.0 + other.0)
+    }
+}
+
+/// the blocks keyed by their first key
+type Blocks<V> = BTree<Vec<u8>, Arc<Block<V>>, Entries>;
+
+/// the index of the last block starting at or before k, 0 if there is none
+fn block_index<V>(blocks: &Blocks<V>, k: &[u8]) -> usize {
+    blocks
+        .root
+        .rank(&|first| first.as_slice() <= k)
+        .saturating_sub(1)
+}
+
+/// A persistent map from byte keys with prefix-compressed leaves.
+///
+/// Every leaf of up to m items is a block storing the prefix shared by its
+/// keys once and only the suffix of every key, the blocks are the items of a
+/// `BTree` keyed by their first key. Keys sharing long prefixes, such as
+/// paths or composite keys, take a fraction of the memory of `BTree<Vec<u8>, V>`.
+/// `get`, `seek`, `split_off` and batch writes behave as they do on that tree,
+/// a batch is merged into every block it touches by the leaf merge of `BTree`.
+/// The items handed out are rebuilt from their block, so unlike the items of
+/// `BTree` they are not shared with the tree. A put or remove copies the
+/// entries of its block, a block that splits or loses its first key is
+/// rebuilt by the batch path.
+///
+/// # Examples
+/// ```rust
+/// use mem_btree::{BatchWrite, CompactBTree};
+///
+/// let mut tree = CompactBTree::new(32);
+/// for i in 0..100u32 {
+///     tree.put(format!("/users/{i:04}/name").into_bytes(), i);
+/// }
+/// let snapshot = tree.clone();
+///
+/// let mut bw = BatchWrite::default();
+/// bw.delete_range(b"/users/0010".to_vec()..b"/users/0090".to_vec());
+/// bw.put(b"/users/0050/name".to_vec(), 500);
+/// tree.write(bw);
+/// assert_eq!(tree.len(), 21);
+/// assert_eq!(tree.get(b"/users/0050/name"), Some(&500));
+/// assert_eq!(snapshot.get(b"/users/0050/name"), Some(&50));
+///
+/// let mut iter = tree.iter();
+/// iter.seek(b"/users/0009");
+/// let keys: Vec<_> = iter.take(2).map(|item| item.0.clone()).collect();
+/// assert_eq!(keys, vec![b"/users/0009/name".to_vec(), b"/users/0050/name".to_vec()]);
+/// ```
+pub struct CompactBTree<V> {
+    m: usize,
+    blocks: Blocks<V>,
+    merge_operator: Option<Arc<dyn MergeOperator<Vec<u8>, V>>>,
+}
+
+/// clone is a snapshot, the same as `BTree`
+impl<V> Clone for CompactBTree<V> {
+    fn clone(&self) -> Self {
+        Self {
+            m: self.m,
+            blocks: self.blocks.clone(),
+            merge_operator: self.merge_operator.clone(),
+        }
+    }
+}
+
+impl<V: Clone> CompactBTree<V> {
+    /// Create a new CompactBTree with a given branching factor,
+    /// it is also the number of items in a block
+    pub fn new(m: usize) -> Self {
+        Self {
+            m,
+            blocks: BTree::with_aggregate(m),
+            merge_operator: None,
+        }
+    }
+
+    /// Set the merge operator used by the merge actions of batches
+    pub fn with_merge_operator<O>(mut self, merge_operator: O) -> Self
+    where
+        O: MergeOperator<Vec<u8>, V> + 'static,
+    {
+        self.merge_operator = Some(Arc::new(merge_operator));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.root.aggregate().0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the value of a key
+    pub fn get(&self, k: &[u8]) -> Option<&V> {
+        let block = &self.blocks.get_index(block_index(&self.blocks, k))?.1;
+        let i = block.search(k).ok()?;
+        Some(&block.entries[i].1)
+    }
+
+    /// Insert a key-value pair, return the old item
+    pub fn put(&mut self, k: Vec<u8>, v: V) -> Option<Item<Vec<u8>, V>> {
+        self.write_one(k, Action::Put(v, None))
+    }
+
+    /// Insert a key-value pair with ttl, return the old item
+    pub fn put_ttl(&mut self, k: Vec<u8>, v: V, ttl: Duration) -> Option<Item<Vec<u8>, V>> {
+        self.write_one(k, Action::Put(v, Some(now().add(ttl))))
+    }
+
+    /// Remove a key, return its item
+    pub fn remove(&mut self, k: &[u8]) -> Option<Item<Vec<u8>, V>> {
+        self.write_one(k.to_vec(), Action::Delete)
+    }
+
+    /// write a single put or delete, return the item it replaced or deleted.
+    /// The key is written in a copy of its block when the block keeps its
+    /// first key and at most m entries, a single put to the block tree,
+    /// otherwise the block is rebuilt and split by the batch path
+    fn write_one(&mut self, k: Vec<u8>, action: Action<V>) -> Option<Item<Vec<u8>, V>> {
+        let index = block_index(&self.blocks, &k);
+        if let Some(b) = self.blocks.get_index(index) {
+            let (first, block) = (b.0.clone(), b.1.clone());
+            if let Some(suffix) = k.strip_prefix(&*block.prefix) {
+                let found = block.entries.binary_search_by(|e| (*e.0).cmp(suffix));
+                let in_place = match (&action, found) {
+                    (Action::Put(..), Ok(_)) => true,
+                    (Action::Put(..), Err(i)) => i > 0 && block.entries.len() < self.m,
+                    (Action::Delete, Ok(i)) => i > 0,
+                    (Action::Delete, Err(_)) => return None,
+                    _ => false,
+                };
+                if in_place {
+                    let mut entries = block.entries.clone();
+                    let old = match action {
+                        Action::Put(v, ttl) => {
+                            let entry = (suffix.into(), v, ttl);
+                            match found {
+                                Ok(i) => Some(std::mem::replace(&mut entries[i], entry)),
+                                Err(i) => {
+                                    entries.insert(i, entry);
+                                    None
+                                }
+                            }
+                        }
+                        _ => found.ok().map(|i| entries.remove(i)),
+                    };
+                    let prefix = block.prefix.clone();
+                    self.blocks.put(first, Arc::new(Block { prefix, entries }));
+                    return old.map(|e| Arc::new((k, e.1, e.2)));
+                }
+            }
+        }
+
+        let mut bw = BatchWrite::default();
+        bw.push(k, action);
+        let mut outcomes = Vec::new();
+        self.inner_write(bw, Some(&mut outcomes));
+        match outcomes.pop() {
+            Some(WriteOutcome::Replaced(old) | WriteOutcome::Deleted(old)) => Some(old),
+            _ => None,
+        }
+    }
+
+    /// Write a batch, like `BTree::write`
+    ///
+    /// # Panics
+    /// Panics if the batch has a merge action and the tree has no merge operator
+    pub fn write(&mut self, batch_write: BatchWrite<Vec<u8>, V>) {
+        if let Err(e) = self.try_write(batch_write) {
+            panic!("{e}");
+        }
+    }
+
+    /// Write a batch like `write`, a batch the tree cannot apply is an error
+    /// and nothing is written
+    pub fn try_write(&mut self, batch_write: BatchWrite<Vec<u8>, V>) -> Result<(), WriteError> {
+        self.check_write(&batch_write)?;
+        self.inner_write(batch_write, None);
+        Ok(())
+    }
+
+    /// Write a batch and return what it did to every key, in key order,
+    /// like `BTree::write_with_outcomes`
+    ///
+    /// # Panics
+    /// Panics like `write`, before anything is written
+    pub fn write_with_outcomes(
+        &mut self,
+        batch_write: BatchWrite<Vec<u8>, V>,
+    ) -> Vec<WriteOutcome<Vec<u8>, V>> {
+        if let Err(e) = self.check_write(&batch_write) {
+            panic!("{e}");
+        }
+        let mut outcomes = Vec::new();
+        self.inner_write(batch_write, Some(&mut outcomes));
+        outcomes
+    }
+
+    fn check_write(&self, batch_write: &BatchWrite<Vec<u8>, V>) -> Result<(), WriteError> {
+        if self.merge_operator.is_none() && batch_write.needs_merge_operator() {
+            return Err(WriteError::NoMergeOperator);
+        }
+        Ok(())
+    }
+
+    /// merge the batch into every block it touches and replace them
+    /// by the merged items split into blocks of at most m items
+    fn inner_write(
+        &mut self,
+        batch_write: BatchWrite<Vec<u8>, V>,
+        mut outcomes: Option<&mut Vec<WriteOutcome<Vec<u8>, V>>>,
+    ) {
+        let blocks = &self.blocks;
+
+        // the blocks overlapping a deleted range
+        let mut touched = Vec::new();
+        for (start, end) in batch_write.ranges() {
+            let first = block_index(blocks, start);
+            let last = blocks.root.rank(&|f| f < end).saturating_sub(1);
+            touched.extend(first..=last);
+        }
+
+        let (actions, ranges) = batch_write.into_parts();
+        let actions: Vec<_> = actions
+            .into_iter()
+            .map(|(k, a)| (block_index(blocks, &k), k, a))
+            .collect();
+        touched.extend(actions.iter().map(|a| a.0));
+        touched.sort_unstable();
+        touched.dedup();
+
+        let ctx = WriteContext {
+            ranges: &ranges,
+            merge_operator: self.merge_operator.as_deref(),
+        };
+
+        // the old blocks are all deleted before the new ones are put,
+        // a new block may start at the first key of an old one
+        let mut changes = BatchWrite::default();
+        let mut merged = Vec::new();
+        let mut actions = actions.into_iter().peekable();
+        for index in touched {
+            let part: Actions<Vec<u8>, V> =
+                std::iter::from_fn(|| actions.next_if(|a| a.0 == index))
+                    .map(|(_, k, a)| (k, a))
+                    .collect();
+            // an empty tree is a single empty block
+            let items = match blocks.get_index(index) {
+                Some(block) => {
+                    changes.delete(block.0.clone());
+                    block.1.items()
+                }
+                None => Vec::new(),
+            };
+            let items = Leaf::<Vec<u8>, V>::merge_sort_arr(
+                items.len() + part.len(),
+                items.iter(),
+                part.into_iter(),
+                &ctx,
+                outcomes.as_deref_mut(),
+            );
+            merged.extend(Block::split(self.m, &items));
+        }
+        for block in merged {
+            changes.put(block.key(0), Arc::new(block));
+        }
+
+        self.blocks.write(changes);
+    }
+
+    /// All the items in key order
+    pub fn iter(&self) -> CompactIter<V> {
+        let mut blocks = self.blocks.iter();
+        let block = blocks.next().map(|b| b.1.clone());
+        CompactIter {
+            blocks,
+            block,
+            index: 0,
+        }
+    }
+
+    /// Split the tree at k, self keeps the keys less than k
+    /// and the keys from k on are returned
+    pub fn split_off(&mut self, k: &[u8]) -> Self {
+        let mut right = self.blocks.split_off(&k.to_vec());
+
+        // the last block on the left may hold keys from k on
+        let last = self
+            .blocks
+            .len()
+            .checked_sub(1)
+            .and_then(|i| self.blocks.get_index(i))
+            .cloned();
+        if let Some(last) = last {
+            let at = last.1.search(k).unwrap_or_else(|i| i);
+            if at < last.1.entries.len() {
+                // its first key is less than k, the left part is not empty
+                let items = last.1.items();
+                self.blocks
+                    .put(last.0.clone(), Arc::new(Block::new(&items[..at])));
+                let tail = Block::new(&items[at..]);
+                right.put(tail.key(0), Arc::new(tail));
+            }
+        }
+
+        Self {
+            m: self.m,
+            blocks: right,
+            merge_operator: self.merge_operator.clone(),
+        }
+    }
+}
+
+/// An iterator over the items of a `CompactBTree`,
+/// the items are rebuilt from the blocks
+pub struct CompactIter<V> {
+    blocks: Iter<Vec<u8>, Arc<Block<V>>, Entries>,
+    /// the current block and the index of the next item in it
+    block: Option<Arc<Block<V>>>,
+    index: usize,
+}
+
+impl<V: Clone> CompactIter<V> {
+    /// seek to the first key not less than k, next returns it
+    pub fn seek(&mut self, k: &[u8]) {
+        self.blocks.seek_index(block_index(&self.blocks.inner, k));
+        self.block = self.blocks.next().map(|b| b.1.clone());
+        self.index = match &self.block {
+            Some(block) => block.search(k).unwrap_or_else(|i| i),
+            None => 0,
+        };
+    }
+}
+
+impl<V: Clone> Iterator for CompactIter<V> {
+    type Item = Item<Vec<u8>, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let block = self.block.as_ref()?;
+            if self.index < block.entries.len() {
+                self.index += 1;
+                return Some(block.item(self.index - 1));
+            }
+            self.block = self.blocks.next().map(|b| b.1.clone());
+            self.index = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::CompactBTree;
+    use crate::{BTree, BatchWrite, Item, WriteOutcome};
+
+    /// keys of a few components sharing long prefixes
+    fn random_key(rng: &mut StdRng) -> Vec<u8> {
+        let mut key = b"/tenants/acme/users/".to_vec();
+        key.extend_from_slice(format!("{:03}", rng.gen_range(0..300)).as_bytes());
+        if rng.gen_bool(0.7) {
+            key.extend_from_slice(format!("/field/{}", rng.gen_range(0..5)).as_bytes());
+        }
+        key
+    }
+
+    fn plain(items: impl Iterator<Item = Item<Vec<u8>, u32>>) -> Vec<(Vec<u8>, u32)> {
+        items.map(|i| (i.0.clone(), i.1)).collect()
+    }
+
+    fn outcomes(outcomes: Vec<WriteOutcome<Vec<u8>, u32>>) -> Vec<String> {
+        outcomes
+            .into_iter()
+            .map(|o| match o {
+                WriteOutcome::Inserted(i) => format!("inserted {:?} {}", i.0, i.1),
+                WriteOutcome::Replaced(i) => format!("replaced {:?} {}", i.0, i.1),
+                WriteOutcome::Deleted(i) => format!("deleted {:?} {}", i.0, i.1),
+                WriteOutcome::DeleteMiss(k) => format!("delete miss {k:?}"),
+                WriteOutcome::Rejected(k) => format!("rejected {k:?}"),
+            })
+            .collect()
+    }
+
+    fn assert_same(compact: &CompactBTree<u32>, btree: &BTree<Vec<u8>, u32>) {
+        assert_eq!(compact.len(), btree.len());
+        assert_eq!(plain(compact.iter()), plain(btree.iter()));
+    }
+
+    #[test]
+    fn test_compact_against_btree() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let add = |_: &Vec<u8>, v: Option<&u32>, op: u32| v.unwrap_or(&0) + op;
+        let mut compact = CompactBTree::new(8).with_merge_operator(add);
+        let mut btree = BTree::new(8).with_merge_operator(add);
+        let mut snapshots = Vec::new();
+
+        for round in 0..300 {
+            if rng.gen_bool(0.5) {
+                let k = random_key(&mut rng);
+                if rng.gen_bool(0.3) {
+                    assert_eq!(compact.remove(&k), btree.remove(&k));
+                } else {
+                    let v = rng.gen_range(0..100);
+                    assert_eq!(compact.put(k.clone(), v), btree.put(k, v));
+                }
+            } else {
+                // the same random actions go into a batch for each tree
+                let ops: Vec<_> = (0..rng.gen_range(0..20))
+                    .map(|_| {
+                        let (k, end) = (random_key(&mut rng), random_key(&mut rng));
+                        (rng.gen_range(0..6), k, end, rng.gen_range(0..100))
+                    })
+                    .collect();
+                let batch = || {
+                    let mut bw = BatchWrite::default();
+                    for (op, k, end, v) in ops.iter().cloned() {
+                        match op {
+                            0 => bw.delete(k),
+                            1 => bw.put_if_absent(k, 1000),
+                            2 => bw.compare_and_set(k, v, 2000),
+                            3 => bw.merge(k, 1),
+                            4 => bw.delete_range(k..end),
+                            _ => bw.put(k, v),
+                        }
+                    }
+                    bw
+                };
+                assert_eq!(
+                    outcomes(compact.write_with_outcomes(batch())),
+                    outcomes(btree.write_with_outcomes(batch()))
+                );
+            }
+            assert_same(&compact, &btree);
+
+            for _ in 0..10 {
+                let k = random_key(&mut rng);
+                assert_eq!(compact.get(&k), btree.get(&k));
+
+                let mut expected = btree.iter();
+                expected.seek(&k);
+                let mut iter = compact.iter();
+                iter.seek(&k);
+                assert_eq!(plain(iter.take(5)), plain(expected.take(5)));
+            }
+
+            if round % 30 == 0 {
+                snapshots.push((compact.clone(), btree.clone()));
+            }
+        }
+
+        // the snapshots did not see the later writes
+        for (compact, btree) in &snapshots {
+            assert_same(compact, btree);
+        }
+    }
+
+    #[test]
+    fn test_compact_split_off() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..50 {
+            let mut compact = CompactBTree::new(4);
+            let mut btree = BTree::new(4);
+            for _ in 0..rng.gen_range(0..200) {
+                let k = random_key(&mut rng);
+                compact.put(k.clone(), 1);
+                btree.put(k, 1);
+            }
+
+            let k = random_key(&mut rng);
+            let compact_right = compact.split_off(&k);
+            let btree_right = btree.split_off(&k);
+            assert_same(&compact, &btree);
+            assert_same(&compact_right, &btree_right);
+        }
+    }
+
+    #[test]
+    fn test_compact_shares_prefix() {
+        let mut compact = CompactBTree::new(16);
+        for i in 0..1000u32 {
+            compact.put(format!("/tenants/acme/users/{i:05}").into_bytes(), i);
+        }
+        let blocks: Vec<_> = compact.blocks.iter().map(|b| b.1.clone()).collect();
+        assert!(blocks
+            .iter()
+            .all(|b| b.prefix.starts_with(b"/tenants/acme/users/")));
+        let stored: usize = blocks
+            .iter()
+            .map(|b| b.prefix.len() + b.entries.iter().map(|e| e.0.len()).sum::<usize>())
+            .sum();
+        assert!(stored * 4 < 1000 * "/tenants/acme/users/00000".len());
+    }
+
+    #[test]
+    fn test_compact_blocks_stay_full() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut compact = CompactBTree::new(16);
+        for _ in 0..2000 {
+            let k = random_key(&mut rng);
+            if rng.gen_bool(0.5) {
+                compact.put(k, 1);
+            } else {
+                let mut bw = BatchWrite::default();
+                bw.put(k, 1);
+                compact.write(bw);
+            }
+        }
+        // overfull blocks split in halves, none is left with a few entries
+        let sizes: Vec<_> = compact.blocks.iter().map(|b| b.1.entries.len()).collect();
+        assert!(sizes.len() > 1);
+        assert!(sizes.iter().all(|&n| (8..=16).contains(&n)), "{sizes:?}");
+    }
+}
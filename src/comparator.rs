@@ -0,0 +1,232 @@
+use std::{
+    cmp::Ordering,
+    ops::{Bound, RangeBounds},
+};
+
+/// The order of the keys of a tree.
+///
+/// It is a type parameter of `BTree` and not a value, the same tree type always
+/// orders its keys the same way, so snapshots and subtrees can be shared freely.
+/// The default `OrdComparator` uses `Ord`.
+///
+/// # Examples
+/// ```rust
+/// use std::cmp::Ordering;
+/// use mem_btree::{BTree, Comparator};
+///
+/// struct CaseInsensitive;
+///
+/// impl Comparator<String> for CaseInsensitive {
+///     fn cmp(a: &String, b: &String) -> Ordering {
+///         a.to_lowercase().cmp(&b.to_lowercase())
+///     }
+/// }
+///
+/// let mut btree: BTree<String, u32, (), CaseInsensitive> = BTree::with_comparator(32);
+/// btree.put("b".to_string(), 1);
+/// btree.put("A".to_string(), 2);
+/// btree.put("B".to_string(), 3);
+/// assert_eq!(btree.get(&"a".to_string()), Some(&2));
+/// let keys: Vec<_> = btree.iter().map(|item| item.0.clone()).collect();
+/// assert_eq!(keys, vec!["A", "B"]);
+/// ```
+pub trait Comparator<K> {
+    fn cmp(a: &K, b: &K) -> Ordering;
+}
+
+/// the order of `Ord`, the default of `BTree`
+pub struct OrdComparator;
+
+impl<K: Ord> Comparator<K> for OrdComparator {
+    #[inline]
+    fn cmp(a: &K, b: &K) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// the reverse order of `Ord`
+pub struct ReverseComparator;
+
+impl<K: Ord> Comparator<K> for ReverseComparator {
+    #[inline]
+    fn cmp(a: &K, b: &K) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+/// k is not before the start of the range in the order of C
+pub(crate) fn after_start<K, C, R>(range: &R, k: &K) -> bool
+where
+    C: Comparator<K>,
+    R: RangeBounds<K>,
+{
+    match range.start_bound() {
+        Bound::Included(s) => C::cmp(k, s).is_ge(),
+        Bound::Excluded(s) => C::cmp(k, s).is_gt(),
+        Bound::Unbounded => true,
+    }
+}
+
+/// k is not after the end of the range in the order of C
+pub(crate) fn before_end<K, C, R>(range: &R, k: &K) -> bool
+where
+    C: Comparator<K>,
+    R: RangeBounds<K>,
+{
+    match range.end_bound() {
+        Bound::Included(e) => C::cmp(k, e).is_le(),
+        Bound::Excluded(e) => C::cmp(k, e).is_lt(),
+        Bound::Unbounded => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cmp::Ordering, collections::BTreeMap, ops::Bound};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{Comparator, ReverseComparator};
+    use crate::{BTree, BatchWrite, WriteOutcome};
+
+    struct CaseInsensitive;
+
+    impl Comparator<String> for CaseInsensitive {
+        fn cmp(a: &String, b: &String) -> Ordering {
+            a.to_lowercase().cmp(&b.to_lowercase())
+        }
+    }
+
+    #[test]
+    fn test_reverse_comparator() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut btree: BTree<i32, u32, (), ReverseComparator> = BTree::with_comparator(4);
+        let mut map = BTreeMap::new();
+
+        for _ in 0..3000 {
+            let k = rng.gen_range(0..500);
+            if rng.gen_bool(0.3) {
+                assert_eq!(btree.remove(&k).map(|i| i.1), map.remove(&k));
+            } else {
+                let v = rng.gen::<u32>();
+                assert_eq!(btree.put(k, v).map(|i| i.1), map.insert(k, v));
+            }
+        }
+
+        let expected: Vec<_> = map.iter().rev().map(|(k, v)| (*k, *v)).collect();
+        let items: Vec<_> = btree.iter().map(|i| (i.0, i.1)).collect();
+        assert_eq!(items, expected);
+        for k in 0..500 {
+            assert_eq!(btree.get(&k), map.get(&k));
+        }
+
+        // seek goes to the first key not before it in the reverse order
+        let mut iter = btree.iter();
+        iter.seek(&250);
+        let first = map.range(..=250).next_back().map(|(k, _)| *k);
+        assert_eq!(iter.next().map(|i| i.0), first);
+
+        // the keys before 250 in the reverse order are the greater ones
+        let mut left = btree.clone();
+        let right = left.split_off(&250);
+        assert!(left.iter().all(|i| i.0 > 250));
+        assert!(right.iter().all(|i| i.0 <= 250));
+        assert_eq!(left.len() + right.len(), map.len());
+        let union = right.union_with(&left, |_, v, _| *v);
+        assert_eq!(
+            union.iter().map(|i| (i.0, i.1)).collect::<Vec<_>>(),
+            expected
+        );
+
+        // a range runs from 400 down to 100 in the reverse order
+        let range = (Bound::Included(400), Bound::Included(100));
+        assert_eq!(btree.remove_range(range), map.range(100..=400).count());
+        assert!(btree.iter().all(|i| !(100..=400).contains(&i.0)));
+    }
+
+    #[test]
+    fn test_batch_write_with_comparator() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut btree: BTree<i32, u32, (), ReverseComparator> = BTree::with_comparator(4);
+        let mut map = BTreeMap::new();
+
+        for _ in 0..200 {
+            let mut bw = BatchWrite::default();
+            let mut expected = map.clone();
+            for _ in 0..30 {
+                let k = rng.gen_range(0..500);
+                let v = rng.gen::<u32>();
+                match rng.gen_range(0..10) {
+                    0 | 1 => {
+                        bw.delete(k);
+                        expected.remove(&k);
+                    }
+                    2 => {
+                        bw.put_if_absent(k, v);
+                        expected.entry(k).or_insert(v);
+                    }
+                    3 => {
+                        // the range runs down from k in the reverse order
+                        bw.delete_range(k..k - 10);
+                        expected.retain(|key, _| !(k - 10 < *key && *key <= k));
+                    }
+                    _ => {
+                        bw.put(k, v);
+                        expected.insert(k, v);
+                    }
+                }
+            }
+            // the outcomes follow the reverse order of the tree
+            let keys: Vec<i32> = btree
+                .write_with_outcomes(bw)
+                .into_iter()
+                .map(|o| match o {
+                    WriteOutcome::Inserted(i)
+                    | WriteOutcome::Replaced(i)
+                    | WriteOutcome::Deleted(i) => i.0,
+                    WriteOutcome::DeleteMiss(k) | WriteOutcome::Rejected(k) => k,
+                })
+                .collect();
+            assert!(keys.windows(2).all(|w| w[0] > w[1]));
+            map = expected;
+        }
+
+        let items: Vec<_> = btree.iter().map(|i| (i.0, i.1)).collect();
+        let expected: Vec<_> = map.iter().rev().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(items, expected);
+
+        let mut buf = Vec::new();
+        btree.write_to(&mut buf).unwrap();
+        let copy: BTree<i32, u32, (), ReverseComparator> =
+            BTree::read_with_comparator(buf.as_slice()).unwrap();
+        assert_eq!(copy.iter().map(|i| (i.0, i.1)).collect::<Vec<_>>(), items);
+        let loaded: BTree<i32, u32, (), ReverseComparator> =
+            BTree::bulk_load_with_comparator(4, items.iter().map(|(k, v)| (*k, *v, None)));
+        assert_eq!(loaded.iter().map(|i| (i.0, i.1)).collect::<Vec<_>>(), items);
+
+        // the keys of a batch differing in case are one key of the tree
+        let mut btree: BTree<String, u32, (), CaseInsensitive> = BTree::with_comparator(4);
+        let mut map = BTreeMap::new();
+        for i in 0..1000 {
+            let mut bw = BatchWrite::default();
+            for _ in 0..5 {
+                let k: String = (0..2).map(|_| rng.gen_range(b'a'..=b'c') as char).collect();
+                let k = if rng.gen_bool(0.5) {
+                    k.to_uppercase()
+                } else {
+                    k
+                };
+                if rng.gen_bool(0.2) {
+                    map.remove(&k.to_lowercase());
+                    bw.delete(k);
+                } else {
+                    map.insert(k.to_lowercase(), i);
+                    bw.put(k, i);
+                }
+            }
+            btree.write(bw);
+        }
+        let items: Vec<_> = btree.iter().map(|i| (i.0.to_lowercase(), i.1)).collect();
+        assert_eq!(items, map.into_iter().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,485 @@
+use crate::*;
+
+/// The nodes from the root down to the item under a cursor, with the index
+/// of the child or item taken in each, empty on the ghost position
+struct Path<K, V, A, C> {
+    stack: Vec<(N<K, V, A, C>, usize)>,
+    /// the index of the item in key order, the length of the tree on the ghost position
+    index: usize,
+}
+
+impl<K, V, A, C> Clone for Path<K, V, A, C> {
+    fn clone(&self) -> Self {
+        Self {
+            stack: self.stack.clone(),
+            index: self.index,
+        }
+    }
+}
+
+impl<K, V, A, C> Path<K, V, A, C>
+where
+    A: Aggregate<K, V>,
+    C: Comparator<K>,
+{
+    /// the path to the item at index, the ghost position past the last item
+    fn seek(root: &N<K, V, A, C>, mut index: usize) -> Self {
+        let len = root.len();
+        if index >= len {
+            return Self {
+                stack: Vec::new(),
+                index: len,
+            };
+        }
+
+        let mut path = Self {
+            stack: Vec::new(),
+            index,
+        };
+        let mut node = root.clone();
+        loop {
+            let (i, child) = match &*node {
+                BTreeType::Leaf(_) => {
+                    path.stack.push((node, index));
+                    return path;
+                }
+                BTreeType::Node(n) => {
+                    let mut i = 0;
+                    while index >= n.children[i].len() {
+                        index -= n.children[i].len();
+                        i += 1;
+                    }
+                    (i, n.children[i].clone())
+                }
+            };
+            path.stack.push((node, i));
+            node = child;
+        }
+    }
+
+    fn item(&self) -> Option<&Item<K, V>> {
+        match self.stack.last() {
+            Some((node, i)) => match &**node {
+                BTreeType::Leaf(l) => l.items.get(*i),
+                BTreeType::Node(_) => None,
+            },
+            None => None,
+        }
+    }
+
+    /// the item after this one, the first item after the ghost position
+    fn peek_next<'a>(&'a self, root: &'a N<K, V, A, C>) -> Option<&'a Item<K, V>> {
+        if root.is_empty() {
+            return None;
+        }
+        if self.stack.is_empty() {
+            return root.key();
+        }
+        for (node, i) in self.stack.iter().rev() {
+            if i + 1 < node.children_len() {
+                return match &**node {
+                    BTreeType::Leaf(l) => l.items.get(i + 1),
+                    BTreeType::Node(n) => n.children[i + 1].key(),
+                };
+            }
+        }
+        None
+    }
+
+    /// the item before this one, the last item before the ghost position
+    fn peek_prev<'a>(&'a self, root: &'a N<K, V, A, C>) -> Option<&'a Item<K, V>> {
+        if self.stack.is_empty() {
+            return root.max();
+        }
+        for (node, i) in self.stack.iter().rev() {
+            if *i > 0 {
+                return match &**node {
+                    BTreeType::Leaf(l) => l.items.get(i - 1),
+                    BTreeType::Node(n) => n.children[i - 1].max(),
+                };
+            }
+        }
+        None
+    }
+
+    /// go to the next item, the tree may only need the leaf at the top of the stack
+    fn move_next(&mut self, root: &N<K, V, A, C>) {
+        if self.stack.is_empty() {
+            *self = Self::seek(root, 0);
+            return;
+        }
+        while let Some((node, i)) = self.stack.last_mut() {
+            if *i + 1 < node.children_len() {
+                *i += 1;
+                let mut child = match &**node {
+                    BTreeType::Leaf(_) => None,
+                    BTreeType::Node(n) => Some(n.children[*i].clone()),
+                };
+                // the first item of the next subtree
+                while let Some(node) = child {
+                    child = match &*node {
+                        BTreeType::Leaf(_) => None,
+                        BTreeType::Node(n) => Some(n.children[0].clone()),
+                    };
+                    self.stack.push((node, 0));
+                }
+                self.index += 1;
+                return;
+            }
+            self.stack.pop();
+        }
+        // past the last item
+        self.index = root.len();
+    }
+
+    /// go to the previous item, the tree may only need the leaf at the top of the stack
+    fn move_prev(&mut self, root: &N<K, V, A, C>) {
+        if self.stack.is_empty() {
+            if let Some(index) = root.len().checked_sub(1) {
+                *self = Self::seek(root, index);
+            }
+            return;
+        }
+        while let Some((node, i)) = self.stack.last_mut() {
+            if *i > 0 {
+                *i -= 1;
+                let mut child = match &**node {
+                    BTreeType::Leaf(_) => None,
+                    BTreeType::Node(n) => Some(n.children[*i].clone()),
+                };
+                // the last item of the previous subtree
+                while let Some(node) = child {
+                    let last = node.children_len() - 1;
+                    child = match &*node {
+                        BTreeType::Leaf(_) => None,
+                        BTreeType::Node(n) => Some(n.children[last].clone()),
+                    };
+                    self.stack.push((node, last));
+                }
+                self.index -= 1;
+                return;
+            }
+            self.stack.pop();
+        }
+        // before the first item
+        self.index = root.len();
+    }
+}
+
+impl<K, V, A, C> BTree<K, V, A, C>
+where
+    A: Aggregate<K, V>,
+    C: Comparator<K>,
+{
+    /// A cursor on the first item of a snapshot of the tree
+    pub fn cursor_front(&self) -> Cursor<K, V, A, C> {
+        Cursor::new(self.clone(), 0)
+    }
+
+    /// A cursor on the first item not before k of a snapshot of the tree
+    pub fn cursor_at(&self, k: &K) -> Cursor<K, V, A, C> {
+        let index = self.root.rank(&|x| C::cmp(x, k).is_lt());
+        Cursor::new(self.clone(), index)
+    }
+
+    /// A cursor on the first item of the tree, which can change the tree
+    pub fn cursor_front_mut(&mut self) -> CursorMut<'_, K, V, A, C> {
+        CursorMut::new(self, 0)
+    }
+
+    /// A cursor on the first item not before k of the tree, which can change the tree
+    pub fn cursor_at_mut(&mut self, k: &K) -> CursorMut<'_, K, V, A, C> {
+        let index = self.root.rank(&|x| C::cmp(x, k).is_lt());
+        CursorMut::new(self, index)
+    }
+}
+
+/// A cursor over a snapshot of a tree.
+///
+/// It stays on an item, or on the ghost position between the last and the
+/// first item, until it is moved. It keeps the path from the root to its
+/// item like `Iter`, so a move or a peek costs O(1) amortized, only placing
+/// it and wrapping around the ghost position descend from the root.
+///
+/// # Examples
+/// ```rust
+/// use mem_btree::BTree;
+/// let mut btree = BTree::new(32);
+/// for i in 0..10 {
+///     btree.put(i, i * 10);
+/// }
+/// let mut cursor = btree.cursor_at(&5);
+/// assert_eq!(cursor.key(), Some(&5));
+/// assert_eq!(cursor.peek_next().map(|i| i.0), Some(6));
+/// assert_eq!(cursor.peek_prev().map(|i| i.0), Some(4));
+/// cursor.move_prev();
+/// assert_eq!(cursor.value(), Some(&40));
+/// ```
+pub struct Cursor<K, V, A = (), C = OrdComparator> {
+    tree: BTree<K, V, A, C>,
+    path: Path<K, V, A, C>,
+}
+
+impl<K, V, A, C> Cursor<K, V, A, C>
+where
+    A: Aggregate<K, V>,
+    C: Comparator<K>,
+{
+    fn new(tree: BTree<K, V, A, C>, index: usize) -> Self {
+        let path = Path::seek(&tree.root, index);
+        Self { tree, path }
+    }
+
+    /// The item under the cursor, none on the ghost position
+    pub fn item(&self) -> Option<&Item<K, V>> {
+        self.path.item()
+    }
+
+    pub fn key(&self) -> Option<&K> {
+        self.item().map(|i| &i.0)
+    }
+
+    pub fn value(&self) -> Option<&V> {
+        self.item().map(|i| &i.1)
+    }
+
+    /// The index of the item under the cursor in key order
+    pub fn index(&self) -> Option<usize> {
+        self.item().map(|_| self.path.index)
+    }
+
+    /// The item move_next would go to, without moving
+    pub fn peek_next(&self) -> Option<&Item<K, V>> {
+        self.path.peek_next(&self.tree.root)
+    }
+
+    /// The item move_prev would go to, without moving
+    pub fn peek_prev(&self) -> Option<&Item<K, V>> {
+        self.path.peek_prev(&self.tree.root)
+    }
+
+    /// Move to the next item, from the last item to the ghost position
+    /// and from the ghost position to the first item
+    pub fn move_next(&mut self) {
+        self.path.move_next(&self.tree.root);
+    }
+
+    /// Move to the previous item, from the first item to the ghost position
+    /// and from the ghost position to the last item
+    pub fn move_prev(&mut self) {
+        self.path.move_prev(&self.tree.root);
+    }
+}
+
+/// A cursor over a tree which can remove or replace the item under it.
+///
+/// It moves like `Cursor`. After a change it stays valid, it is on the item
+/// after a removed one and on the new item after a replace, the path to it
+/// is found again from the new root.
+///
+/// # Examples
+/// ```rust
+/// use mem_btree::BTree;
+/// let mut btree = BTree::new(32);
+/// for i in 0..10 {
+///     btree.put(i, i);
+/// }
+/// let mut cursor = btree.cursor_front_mut();
+/// while let Some(&k) = cursor.key() {
+///     if k % 2 == 0 {
+///         cursor.remove_current();
+///     } else {
+///         cursor.replace_current(k * 10);
+///         cursor.move_next();
+///     }
+/// }
+/// let items: Vec<_> = btree.iter().map(|i| (i.0, i.1)).collect();
+/// assert_eq!(items, vec![(1, 10), (3, 30), (5, 50), (7, 70), (9, 90)]);
+/// ```
+pub struct CursorMut<'a, K, V, A = (), C = OrdComparator> {
+    tree: &'a mut BTree<K, V, A, C>,
+    path: Path<K, V, A, C>,
+}
+
+impl<'a, K, V, A, C> CursorMut<'a, K, V, A, C>
+where
+    A: Aggregate<K, V>,
+    C: Comparator<K>,
+{
+    fn new(tree: &'a mut BTree<K, V, A, C>, index: usize) -> Self {
+        let path = Path::seek(&tree.root, index);
+        Self { tree, path }
+    }
+
+    /// The item under the cursor, none on the ghost position
+    pub fn item(&self) -> Option<&Item<K, V>> {
+        self.path.item()
+    }
+
+    pub fn key(&self) -> Option<&K> {
+        self.item().map(|i| &i.0)
+    }
+
+    pub fn value(&self) -> Option<&V> {
+        self.item().map(|i| &i.1)
+    }
+
+    /// The index of the item under the cursor in key order
+    pub fn index(&self) -> Option<usize> {
+        self.item().map(|_| self.path.index)
+    }
+
+    /// The item move_next would go to, without moving
+    pub fn peek_next(&self) -> Option<&Item<K, V>> {
+        self.path.peek_next(&self.tree.root)
+    }
+
+    /// The item move_prev would go to, without moving
+    pub fn peek_prev(&self) -> Option<&Item<K, V>> {
+        self.path.peek_prev(&self.tree.root)
+    }
+
+    /// Move to the next item like `Cursor::move_next`
+    pub fn move_next(&mut self) {
+        self.path.move_next(&self.tree.root);
+    }
+
+    /// Move to the previous item like `Cursor::move_prev`
+    pub fn move_prev(&mut self) {
+        self.path.move_prev(&self.tree.root);
+    }
+
+    /// Remove the item under the cursor and return it,
+    /// the cursor goes to the item after it
+    pub fn remove_current(&mut self) -> Option<Item<K, V>> {
+        let item = self.path.item()?.clone();
+        let removed = self.tree.remove(&item.0);
+        self.path = Path::seek(&self.tree.root, self.path.index);
+        removed
+    }
+
+    /// Replace the value of the item under the cursor, the ttl is kept,
+    /// return the old item
+    pub fn replace_current(&mut self, v: V) -> Option<Item<K, V>>
+    where
+        K: Clone,
+    {
+        let item = self.path.item()?;
+        let old = self.tree.inner_put(item.0.clone(), v, item.2);
+        self.path = Path::seek(&self.tree.root, self.path.index);
+        old
+    }
+
+    /// A read-only cursor at the same position over a snapshot of the tree
+    pub fn as_cursor(&self) -> Cursor<K, V, A, C> {
+        Cursor {
+            tree: self.tree.clone(),
+            path: self.path.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::BTree;
+
+    #[test]
+    fn test_cursor() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut btree = BTree::new(4);
+
+        // an empty tree only has the ghost position
+        let mut cursor = btree.cursor_front();
+        cursor.move_next();
+        cursor.move_prev();
+        assert!(cursor.item().is_none());
+        assert!(cursor.peek_next().is_none() && cursor.peek_prev().is_none());
+
+        for i in 0..300 {
+            btree.put(i * 2, i);
+        }
+        let all: Vec<(i32, i32)> = btree.iter().map(|i| (i.0, i.1)).collect();
+        let n = all.len();
+
+        let mut cursor = btree.cursor_front();
+        // the index of the cursor, n is the ghost position
+        let mut index = 0;
+        for _ in 0..2000 {
+            match rng.gen_range(0..5) {
+                0 => {
+                    let k = rng.gen_range(-5..605);
+                    cursor = btree.cursor_at(&k);
+                    index = all.partition_point(|i| i.0 < k);
+                }
+                1 | 2 => {
+                    cursor.move_next();
+                    index = if index == n { 0 } else { index + 1 };
+                }
+                _ => {
+                    cursor.move_prev();
+                    index = if index == 0 { n } else { index - 1 };
+                }
+            }
+            let at = |i: usize| all.get(i).cloned();
+            assert_eq!(cursor.item().map(|i| (i.0, i.1)), at(index));
+            assert_eq!(cursor.index(), at(index).map(|_| index));
+            let next = if index == n { 0 } else { index + 1 };
+            let prev = if index == 0 { n } else { index - 1 };
+            assert_eq!(cursor.peek_next().map(|i| (i.0, i.1)), at(next));
+            assert_eq!(cursor.peek_prev().map(|i| (i.0, i.1)), at(prev));
+        }
+    }
+
+    #[test]
+    fn test_cursor_mut() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut btree = BTree::new(4);
+        let mut map = BTreeMap::new();
+        for i in 0..500 {
+            btree.put(i, i);
+            map.insert(i, i);
+        }
+        let snapshot = btree.clone();
+
+        let mut cursor = btree.cursor_at_mut(&100);
+        for _ in 0..3000 {
+            let key = cursor.key().cloned();
+            match rng.gen_range(0..6) {
+                0 => {
+                    let removed = cursor.remove_current().map(|i| (i.0, i.1));
+                    let expected = key.and_then(|k| map.remove_entry(&k));
+                    assert_eq!(removed, expected);
+                    // the cursor is on the item after the removed one
+                    if let Some(k) = key {
+                        assert_eq!(cursor.key(), map.range(k..).next().map(|(k, _)| k));
+                    }
+                }
+                1 => {
+                    let v = rng.gen_range(1000..2000);
+                    let old = cursor.replace_current(v).map(|i| i.1);
+                    assert_eq!(old, key.and_then(|k| map.insert(k, v)));
+                    assert_eq!(cursor.key().cloned(), key);
+                    if key.is_some() {
+                        assert_eq!(cursor.value(), Some(&v));
+                    }
+                }
+                2 | 3 => cursor.move_next(),
+                _ => cursor.move_prev(),
+            }
+            let next = match cursor.key() {
+                Some(k) => map.range(k + 1..).next(),
+                None => map.iter().next(),
+            };
+            assert_eq!(cursor.peek_next().map(|i| (&i.0, &i.1)), next);
+        }
+        drop(cursor);
+
+        let items: Vec<_> = btree.iter().map(|i| (i.0, i.1)).collect();
+        assert_eq!(items, map.into_iter().collect::<Vec<_>>());
+        assert_eq!(snapshot.len(), 500);
+    }
+}
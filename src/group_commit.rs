@@ -0,0 +1,284 @@
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+use crate::*;
+
+struct Request<K, V> {
+    batch_write: BatchWrite<K, V>,
+    done: Sender<Result<(), WriteError>>,
+}
+
+/// A writer thread in front of a SharedBTree.
+///
+/// Batches submitted by many threads are queued, the queued batches are
+/// coalesced into one batch in the order they arrived, and it is written and
+/// published by one `BTree::write`, so a group of small batches costs one
+/// path copy and one root swap. A batch the tree cannot apply is rejected
+/// alone before its group is coalesced, the rest of the group is written.
+///
+/// # Examples
+/// ```rust
+/// use std::sync::Arc;
+/// use mem_btree::{BatchWrite, GroupCommit, SharedBTree};
+///
+/// let shared = Arc::new(SharedBTree::new(32));
+/// let group_commit = Arc::new(GroupCommit::new(shared.clone()));
+///
+/// let handles: Vec<_> = (0..4)
+///     .map(|t| {
+///         let group_commit = group_commit.clone();
+///         std::thread::spawn(move || {
+///             for i in 0..10 {
+///                 let mut bw = BatchWrite::default();
+///                 bw.put(t * 10 + i, i);
+///                 group_commit.submit(bw).unwrap();
+///             }
+///         })
+///     })
+///     .collect();
+/// for h in handles {
+///     h.join().unwrap();
+/// }
+/// assert_eq!(shared.len(), 40);
+/// ```
+pub struct GroupCommit<K, V> {
+    shared: Arc<SharedBTree<K, V>>,
+    sender: Option<Sender<Request<K, V>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl<K, V> GroupCommit<K, V>
+where
+    K: Ord + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    /// Start the writer thread for the shared tree
+    pub fn new(shared: Arc<SharedBTree<K, V>>) -> Self {
+        let (sender, receiver) = channel();
+
+        let handle = {
+            let shared = shared.clone();
+            std::thread::spawn(move || Self::run(shared, receiver))
+        };
+
+        Self {
+            shared,
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    fn run(shared: Arc<SharedBTree<K, V>>, receiver: Receiver<Request<K, V>>) {
+        while let Ok(first) = receiver.recv() {
+            let mut requests = vec![first];
+            while let Ok(next) = receiver.try_recv() {
+                requests.push(next);
+            }
+
+            let mut waiters = Vec::with_capacity(requests.len());
+            // a panic fails this group only, the writer keeps serving
+            let written = catch_unwind(AssertUnwindSafe(|| {
+                shared.update(|tree| {
+                    let mut batch_write = BatchWrite::default();
+                    for request in requests {
+                        match tree.check_write(&request.batch_write) {
+                            Ok(()) => {
+                                batch_write.append(request.batch_write);
+                                waiters.push(request.done);
+                            }
+                            Err(e) => {
+                                let _ = request.done.send(Err(e));
+                            }
+                        }
+                    }
+                    tree.write(batch_write)
+                })
+            }));
+
+            let result = written.map_err(|_| WriteError::Panicked);
+            for done in waiters {
+                let _ = done.send(result.clone());
+            }
+        }
+    }
+
+    /// Queue a batch and wait until it is published in the shared tree.
+    /// A batch the tree cannot apply is an error and is not written,
+    /// `WriteError::Panicked` if the group holding the batch panicked
+    pub fn submit(&self, batch_write: BatchWrite<K, V>) -> Result<(), WriteError> {
+        let (done, wait) = channel();
+
+        let sender = self.sender.as_ref().unwrap();
+        sender
+            .send(Request { batch_write, done })
+            .expect("group commit writer is stopped");
+
+        // the writer drops the request if the group panicked before it was checked
+        wait.recv().unwrap_or(Err(WriteError::Panicked))
+    }
+
+    /// The shared tree the batches are written into
+    pub fn shared(&self) -> &Arc<SharedBTree<K, V>> {
+        &self.shared
+    }
+}
+
+impl<K, V> Drop for GroupCommit<K, V> {
+    /// stop the writer thread after the queued batches are written
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::GroupCommit;
+    use crate::{BTree, BatchWrite, SharedBTree, WriteError};
+
+    #[test]
+    fn test_submit_is_visible() {
+        let shared = Arc::new(SharedBTree::new(8));
+        let group_commit = Arc::new(GroupCommit::new(shared.clone()));
+
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let group_commit = group_commit.clone();
+                std::thread::spawn(move || {
+                    for i in 0..200 {
+                        let key = t * 1000 + i;
+                        let mut bw = BatchWrite::default();
+                        bw.put(key, i);
+                        group_commit.submit(bw).unwrap();
+                        // visible as soon as submit returns
+                        let shared = group_commit.shared();
+                        assert_eq!(shared.get(&key).map(|item| item.1), Some(i));
+                    }
+                })
+            })
+            .collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(shared.len(), 8 * 200);
+    }
+
+    #[test]
+    fn test_append_same_as_sequence() {
+        let mut rng = StdRng::seed_from_u64(42);
+
+        let mut base = BTree::new(8);
+        for i in 0..100 {
+            base.put(i, i % 4);
+        }
+
+        for _ in 0..100 {
+            let batches: Vec<Vec<(u8, i32, i32)>> = (0..4)
+                .map(|_| {
+                    (0..20)
+                        .map(|_| {
+                            (
+                                rng.gen_range(0..5),
+                                rng.gen_range(0..120),
+                                rng.gen_range(0..4),
+                            )
+                        })
+                        .collect()
+                })
+                .collect();
+
+            let make = |ops: &Vec<(u8, i32, i32)>| {
+                let mut bw = BatchWrite::default();
+                for (op, k, v) in ops {
+                    match op {
+                        0 => bw.delete(*k),
+                        1 => bw.put_if_absent(*k, *v),
+                        2 => bw.compare_and_set(*k, *v, *v + 1),
+                        3 => bw.delete_range(*k..*k + 3),
+                        _ => bw.put(*k, *v),
+                    }
+                }
+                bw
+            };
+
+            let mut one_by_one = base.clone();
+            let mut coalesced = BatchWrite::default();
+            for ops in batches.iter() {
+                one_by_one.write(make(ops));
+                coalesced.append(make(ops));
+            }
+            let mut grouped = base.clone();
+            grouped.write(coalesced);
+
+            let collect = |t: &BTree<i32, i32>| -> BTreeMap<i32, i32> {
+                t.iter().map(|i| (i.0, i.1)).collect()
+            };
+            assert_eq!(collect(&one_by_one), collect(&grouped));
+        }
+    }
+
+    #[test]
+    fn test_failed_group_keeps_writer() {
+        let shared = Arc::new(SharedBTree::new(8));
+        let group_commit = Arc::new(GroupCommit::new(shared.clone()));
+
+        // batches without a merge operator are rejected alone,
+        // the other batches of their groups are written
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let group_commit = group_commit.clone();
+                std::thread::spawn(move || {
+                    for i in 0..100 {
+                        let mut bw = BatchWrite::default();
+                        bw.put(t * 1000 + i, i);
+                        if t == 0 && i % 2 == 0 {
+                            bw.merge(t * 1000 + i, i);
+                            let result = group_commit.submit(bw);
+                            assert_eq!(result, Err(WriteError::NoMergeOperator));
+                        } else {
+                            group_commit.submit(bw).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(shared.len(), 350);
+        assert!(shared.get(&0).is_none());
+        assert!(shared.get(&1).is_some());
+    }
+
+    #[test]
+    fn test_panicked_group() {
+        let tree = BTree::new(8).with_merge_operator(|_: &i32, _: Option<&i32>, _: i32| -> i32 {
+            panic!("merge failed")
+        });
+        let shared = Arc::new(SharedBTree::from(tree));
+        let group_commit = GroupCommit::new(shared.clone());
+
+        let mut bw = BatchWrite::default();
+        bw.merge(1, 1);
+        assert_eq!(group_commit.submit(bw), Err(WriteError::Panicked));
+        assert!(shared.is_empty());
+
+        let mut bw = BatchWrite::default();
+        bw.put(2, 2);
+        group_commit.submit(bw).unwrap();
+        assert_eq!(shared.len(), 1);
+    }
+}
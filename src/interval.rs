@@ -0,0 +1,274 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::*;
+
+/// the largest end of the intervals of a subtree
+#[derive(Clone)]
+pub struct MaxEnd<K>(Option<K>);
+
+impl<K: Ord + Clone, V> Aggregate<(K, K), V> for MaxEnd<K> {
+    fn identity() -> Self {
+        MaxEnd(None)
+    }
+
+    fn from_item(key: &(K, K), _: &V) -> Self {
+        MaxEnd(Some(key.1.clone()))
+    }
+
+    fn combine(&self, other: &Self) -> Self {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) if a >= b => self.clone(),
+            (_, Some(_)) => other.clone(),
+            _ => self.clone(),
+        }
+    }
+}
+
+type IntervalNode<K, V> = N<(K, K), V, MaxEnd<K>>;
+
+/// A map of closed intervals `[start, end]` ordered by start.
+///
+/// It is a `BTree` keyed by `(start, end)` where every node keeps the largest
+/// end of its intervals, so the subtrees ending before a query are skipped.
+/// Intervals with the same start and different ends are kept apart.
+///
+/// # Examples
+/// ```rust
+/// use mem_btree::IntervalTree;
+///
+/// let mut windows = IntervalTree::new(32);
+/// windows.insert(1, 5, "a");
+/// windows.insert(3, 4, "b");
+/// windows.insert(6, 9, "c");
+///
+/// let found: Vec<_> = windows.overlapping(4..=6).map(|item| item.1).collect();
+/// assert_eq!(found, vec!["a", "b", "c"]);
+/// let found: Vec<_> = windows.stabbing(&5).map(|item| item.1).collect();
+/// assert_eq!(found, vec!["a"]);
+/// ```
+pub struct IntervalTree<K, V> {
+    tree: BTree<(K, K), V, MaxEnd<K>>,
+}
+
+/// clone is a snapshot, the same as `BTree`
+impl<K, V> Clone for IntervalTree<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+        }
+    }
+}
+
+impl<K, V> IntervalTree<K, V>
+where
+    K: Ord + Clone,
+{
+    /// Create a new IntervalTree with a given branching factor
+    pub fn new(m: usize) -> Self {
+        Self {
+            tree: BTree::with_aggregate(m),
+        }
+    }
+
+    /// Insert the interval `[start, end]`, return the old item of the same interval
+    ///
+    /// # Panics
+    /// Panics if start is greater than end
+    pub fn insert(&mut self, start: K, end: K, v: V) -> Option<Item<(K, K), V>> {
+        assert!(start <= end, "interval start is greater than its end");
+        self.tree.put((start, end), v)
+    }
+
+    /// Remove the interval `[start, end]`, return its item
+    pub fn remove(&mut self, start: K, end: K) -> Option<Item<(K, K), V>> {
+        self.tree.remove(&(start, end))
+    }
+
+    /// Get the value of the interval `[start, end]`
+    pub fn get(&self, start: K, end: K) -> Option<&V> {
+        self.tree.get(&(start, end))
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// All the intervals ordered by start then end
+    pub fn iter(&self) -> Iter<(K, K), V, MaxEnd<K>> {
+        self.tree.iter()
+    }
+
+    /// The intervals sharing a point with the range, ordered by start then end
+    pub fn overlapping<R: RangeBounds<K>>(&self, range: R) -> Overlapping<K, V> {
+        Overlapping {
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            stack: vec![(self.tree.root.clone(), 0)],
+        }
+    }
+
+    /// The intervals holding the point, ordered by start then end
+    pub fn stabbing(&self, point: &K) -> Overlapping<K, V> {
+        self.overlapping(point..=point)
+    }
+}
+
+/// An iterator over the intervals overlapping a range.
+///
+/// It walks the tree in order, a subtree whose largest end is before the range
+/// is skipped and the walk stops at the first start after the range.
+pub struct Overlapping<K, V> {
+    start: Bound<K>,
+    end: Bound<K>,
+    /// the nodes on the path to the position and the index of their next entry
+    stack: Vec<(IntervalNode<K, V>, usize)>,
+}
+
+impl<K, V> Overlapping<K, V>
+where
+    K: Ord + Clone,
+{
+    /// an interval ending at end may reach the range
+    fn ends_in(&self, end: &K) -> bool {
+        match &self.start {
+            Bound::Included(s) => end >= s,
+            Bound::Excluded(s) => end > s,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// an interval starting at start may reach the range
+    fn starts_in(&self, start: &K) -> bool {
+        match &self.end {
+            Bound::Included(e) => start <= e,
+            Bound::Excluded(e) => start < e,
+            Bound::Unbounded => true,
+        }
+    }
+}
+
+impl<K, V> Iterator for Overlapping<K, V>
+where
+    K: Ord + Clone,
+{
+    type Item = Item<(K, K), V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, index)) = self.stack.last_mut() {
+            let i = *index;
+            *index += 1;
+            match node.as_ref() {
+                BTreeType::Leaf(leaf) => {
+                    let Some(item) = leaf.items.get(i).cloned() else {
+                        self.stack.pop();
+                        continue;
+                    };
+                    if !self.starts_in(&item.0 .0) {
+                        self.stack.clear();
+                        return None;
+                    }
+                    if self.ends_in(&item.0 .1) {
+                        return Some(item);
+                    }
+                }
+                BTreeType::Node(n) => {
+                    let Some(child) = n.children.get(i).cloned() else {
+                        self.stack.pop();
+                        continue;
+                    };
+                    if !matches!(child.key(), Some(first) if self.starts_in(&first.0 .0)) {
+                        self.stack.clear();
+                        return None;
+                    }
+                    if matches!(&child.aggregate().0, Some(end) if self.ends_in(end)) {
+                        self.stack.push((child, 0));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::{Bound, RangeBounds};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::IntervalTree;
+
+    fn bound(rng: &mut StdRng) -> Bound<i32> {
+        match rng.gen_range(0..3) {
+            0 => Bound::Included(rng.gen_range(-10..1010)),
+            1 => Bound::Excluded(rng.gen_range(-10..1010)),
+            _ => Bound::Unbounded,
+        }
+    }
+
+    /// [s, e] shares a point with the range
+    fn overlaps(range: &(Bound<i32>, Bound<i32>), s: i32, e: i32) -> bool {
+        let after_start = match range.start_bound() {
+            Bound::Included(a) => e >= *a,
+            Bound::Excluded(a) => e > *a,
+            Bound::Unbounded => true,
+        };
+        let before_end = match range.end_bound() {
+            Bound::Included(b) => s <= *b,
+            Bound::Excluded(b) => s < *b,
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    #[test]
+    fn test_interval_tree() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut tree = IntervalTree::new(4);
+        let mut intervals = std::collections::BTreeMap::new();
+
+        for _ in 0..20 {
+            for _ in 0..100 {
+                let s = rng.gen_range(0..1000);
+                let e = s + rng.gen_range(0..200);
+                if rng.gen_bool(0.2) && !intervals.is_empty() {
+                    let (&(s, e), _) = intervals
+                        .iter()
+                        .nth(rng.gen_range(0..intervals.len()))
+                        .unwrap();
+                    assert!(tree.remove(s, e).is_some());
+                    intervals.remove(&(s, e));
+                } else {
+                    let v = rng.gen::<u32>();
+                    tree.insert(s, e, v);
+                    intervals.insert((s, e), v);
+                }
+            }
+            assert_eq!(tree.len(), intervals.len());
+
+            for _ in 0..50 {
+                let range = (bound(&mut rng), bound(&mut rng));
+                let got: Vec<_> = tree.overlapping(range).map(|i| (i.0, i.1)).collect();
+                let expected: Vec<_> = intervals
+                    .iter()
+                    .filter(|((s, e), _)| overlaps(&range, *s, *e))
+                    .map(|(k, v)| (*k, *v))
+                    .collect();
+                assert_eq!(got, expected);
+
+                let point = rng.gen_range(-10..1210);
+                let got: Vec<_> = tree.stabbing(&point).map(|i| i.0).collect();
+                let expected: Vec<_> = intervals
+                    .keys()
+                    .filter(|(s, e)| *s <= point && point <= *e)
+                    .cloned()
+                    .collect();
+                assert_eq!(got, expected);
+            }
+        }
+    }
+}
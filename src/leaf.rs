@@ -1,19 +1,35 @@
-use crate::*;
+use std::marker::PhantomData;
 
-pub struct Leaf<K, V> {
+use crate::{
+    batch_write::{Actions, Current, WriteContext, WriteOutcome},
+    *,
+};
+
+pub struct Leaf<K, V, A = (), C = OrdComparator> {
     pub items: Vec<Item<K, V>>,
+    pub aggregate: A,
+    /// the keys are ordered by C
+    comparator: PhantomData<fn() -> C>,
 }
 
-impl<K, V> Leaf<K, V>
+impl<K, V, A, C> Leaf<K, V, A, C>
 where
-    K: Ord,
+    A: Aggregate<K, V>,
+    C: Comparator<K>,
 {
-    pub fn instance(items: Vec<Item<K, V>>) -> N<K, V> {
-        Arc::new(BTreeType::Leaf(Self { items }))
+    pub fn instance(items: Vec<Item<K, V>>) -> N<K, V, A, C> {
+        let aggregate = items
+            .iter()
+            .fold(A::identity(), |a, i| a.combine(&A::from_item(&i.0, &i.1)));
+        Arc::new(BTreeType::Leaf(Self {
+            items,
+            aggregate,
+            comparator: PhantomData,
+        }))
     }
 
     fn sort_insert(items: &mut Vec<Item<K, V>>, mut item: Item<K, V>) -> Option<Item<K, V>> {
-        match items.binary_search_by(|i| i.0.cmp(&item.0)) {
+        match items.binary_search_by(|i| C::cmp(&i.0, &item.0)) {
             Ok(i) => {
                 std::mem::swap(&mut items[i], &mut item);
                 Some(item)
@@ -25,7 +41,7 @@ where
         }
     }
 
-    pub fn put(&self, m: usize, k: K, v: V, ttl: Option<Duration>) -> PutResult<K, V> {
+    pub fn put(&self, m: usize, k: K, v: V, ttl: Option<Duration>) -> PutResult<K, V, A, C> {
         let mut item = Arc::new((k, v, ttl));
 
         if self.items.len() < m {
@@ -39,7 +55,7 @@ where
         let mut left = self.items[..mid].to_vec();
         let mut right = self.items[mid..].to_vec();
 
-        let old = match item.0.cmp(&self.items[mid].0) {
+        let old = match C::cmp(&item.0, &self.items[mid].0) {
             std::cmp::Ordering::Less => Self::sort_insert(&mut left, item),
             std::cmp::Ordering::Equal => {
                 std::mem::swap(&mut right[0], &mut item);
@@ -51,19 +67,38 @@ where
         (vec![Self::instance(left), Self::instance(right)], old)
     }
 
-    pub fn get(&self, k: &K) -> Option<&V> {
-        if let Ok(i) = self.items.binary_search_by(|v| v.0.cmp(k)) {
-            return Some(&self.items[i].1);
+    /// put the operand combined with the value of k by the merge operator,
+    /// the ttl of the old item is kept
+    pub fn merge(
+        &self,
+        m: usize,
+        k: K,
+        operand: V,
+        merge_operator: &dyn MergeOperator<K, V>,
+    ) -> PutResult<K, V, A, C> {
+        let (v, ttl) = match self.search_index(&k) {
+            Ok(i) => {
+                let old = &self.items[i];
+                (merge_operator.merge(&k, Some(&old.1), operand), old.2)
+            }
+            Err(_) => (merge_operator.merge(&k, None, operand), None),
+        };
+        self.put(m, k, v, ttl)
+    }
+
+    pub fn get(&self, k: &K) -> Option<&Item<K, V>> {
+        if let Ok(i) = self.items.binary_search_by(|v| C::cmp(&v.0, k)) {
+            return Some(&self.items[i]);
         }
         None
     }
 
     pub fn search_index(&self, k: &K) -> Result<usize, usize> {
-        self.items.binary_search_by(|v| v.0.cmp(k))
+        self.items.binary_search_by(|v| C::cmp(&v.0, k))
     }
 
-    pub fn remove(&self, k: &K) -> Option<(N<K, V>, Item<K, V>)> {
-        if let Ok(i) = self.items.binary_search_by(|v| v.0.cmp(k)) {
+    pub fn remove(&self, k: &K) -> RemoveResult<K, V, A, C> {
+        if let Ok(i) = self.items.binary_search_by(|v| C::cmp(&v.0, k)) {
             let mut items = Vec::with_capacity(self.items.len() - 1);
             items.extend_from_slice(&self.items[..i]);
             items.extend_from_slice(&self.items[i + 1..]);
@@ -72,7 +107,7 @@ where
         None
     }
 
-    pub fn expir(&self) -> Option<N<K, V>> {
+    pub fn expir(&self) -> Option<N<K, V, A, C>> {
         let now = now();
 
         let items: Vec<Item<K, V>> = self
@@ -89,11 +124,19 @@ where
         }
     }
 
-    pub fn write(&self, m: usize, bw: BTreeMap<K, Action<V>>) -> Vec<N<K, V>> {
+    pub fn write(
+        &self,
+        m: usize,
+        bw: Actions<K, V>,
+        ctx: &WriteContext<K, V, C>,
+        outcomes: Option<&mut Vec<WriteOutcome<K, V>>>,
+    ) -> Vec<N<K, V, A, C>> {
         let items = Self::merge_sort_arr(
             self.items.len() + bw.len(),
             self.items.iter(),
             bw.into_iter(),
+            ctx,
+            outcomes,
         );
 
         items
@@ -112,11 +155,12 @@ where
         self.items.len()
     }
 
-    pub fn split_off(&self, k: &K) -> (N<K, V>, N<K, V>) {
-        let index = self
-            .items
-            .binary_search_by(|v| v.0.cmp(k))
-            .unwrap_or_else(|i| i);
+    pub fn split_off(&self, k: &K, inclusive: bool) -> SplitResult<K, V, A, C> {
+        let index = match self.items.binary_search_by(|v| C::cmp(&v.0, k)) {
+            Ok(i) if !inclusive => i + 1,
+            Ok(i) => i,
+            Err(i) => i,
+        };
 
         let (left, right) = self.items.split_at(index);
         (
@@ -125,62 +169,71 @@ where
         )
     }
 
-    fn merge_sort_arr(
+    pub(crate) fn merge_sort_arr(
         new_len: usize,
         mut iter1: std::slice::Iter<'_, Item<K, V>>,
-        mut iter2: std::collections::btree_map::IntoIter<K, Action<V>>,
+        mut iter2: std::vec::IntoIter<(K, Action<V>)>,
+        ctx: &WriteContext<K, V, C>,
+        mut outcomes: Option<&mut Vec<WriteOutcome<K, V>>>,
     ) -> Vec<Item<K, V>> {
+        let ranges = ctx.ranges;
         let mut result = Vec::with_capacity(new_len);
         let mut v1 = iter1.next().cloned();
         let mut v2 = iter2.next();
         loop {
-            match (&v1, &v2) {
+            let ord = match (&v1, &v2) {
                 (None, None) => break,
-                (None, Some(_)) => match v2 {
-                    Some((_, Action::Delete)) => {
-                        v2 = iter2.next();
-                    }
-                    Some((k, Action::Put(v, ttl))) => {
-                        result.push(Arc::new((k, v, ttl)));
-                        v2 = iter2.next();
-                    }
-                    None => unreachable!(),
-                },
-                (Some(i), None) => {
-                    result.push(i.clone());
-                    v1 = iter1.next().cloned();
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (Some(i), Some((k, _))) => C::cmp(&i.0, k),
+            };
+
+            let old = if ord == std::cmp::Ordering::Greater {
+                None
+            } else {
+                std::mem::replace(&mut v1, iter1.next().cloned())
+            };
+
+            if ord == std::cmp::Ordering::Less {
+                // an item without action is kept unless it is in a deleted range
+                let old = old.unwrap();
+                if ranges.is_empty() || !ranges.contains(&old.0) {
+                    result.push(old);
+                } else if let Some(o) = outcomes.as_deref_mut() {
+                    o.push(WriteOutcome::Deleted(old));
                 }
-                (Some(i), Some((k, i2))) => match i.0.cmp(k) {
-                    std::cmp::Ordering::Less => {
-                        result.push(i.clone());
-                        v1 = iter1.next().cloned();
+                continue;
+            }
+
+            let (k, action) = std::mem::replace(&mut v2, iter2.next()).unwrap();
+
+            // an item in a deleted range is absent for the action
+            let mut current = match &old {
+                Some(o) if ranges.is_empty() || !ranges.contains(&o.0) => Current::Old(o.clone()),
+                _ => Current::Absent,
+            };
+            let held = action.apply(&k, &mut current, ctx.merge_operator);
+
+            let (new, k) = match current {
+                Current::Absent => (None, Some(k)),
+                Current::Old(item) => (Some(item), Some(k)),
+                Current::New(v, ttl) => (Some(Arc::new((k, v, ttl))), None),
+            };
+
+            if let Some(o) = outcomes.as_deref_mut() {
+                o.push(match (old, &new) {
+                    (Some(old), Some(new)) if Arc::ptr_eq(&old, new) => {
+                        WriteOutcome::Rejected(k.unwrap())
                     }
-                    std::cmp::Ordering::Equal => match i2 {
-                        Action::Delete => {
-                            v1 = iter1.next().cloned();
-                            v2 = iter2.next();
-                        }
-                        Action::Put(_, _) => {
-                            let (k, v) = v2.unwrap();
-                            let (v, ttl) = v.value();
-                            result.push(Arc::new((k, v, ttl)));
-                            v1 = iter1.next().cloned();
-                            v2 = iter2.next();
-                        }
-                    },
-                    std::cmp::Ordering::Greater => match i2 {
-                        Action::Delete => {
-                            v2 = iter2.next();
-                        }
-                        Action::Put(_, _) => {
-                            let (k, v) = v2.unwrap();
-                            let (v, ttl) = v.value();
-                            result.push(Arc::new((k, v, ttl)));
-                            v2 = iter2.next();
-                        }
-                    },
-                },
+                    (Some(old), Some(_)) => WriteOutcome::Replaced(old),
+                    (Some(old), None) => WriteOutcome::Deleted(old),
+                    (None, Some(new)) => WriteOutcome::Inserted(new.clone()),
+                    (None, None) if held => WriteOutcome::DeleteMiss(k.unwrap()),
+                    (None, None) => WriteOutcome::Rejected(k.unwrap()),
+                });
             }
+
+            result.extend(new);
         }
 
         result
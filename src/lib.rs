@@ -12,11 +12,32 @@
 //! * prev iter ✅
 //! * next iter ✅
 //! * batch_write ✅
+//! * remove_range ✅
+//! * shared snapshot for threads ✅
+//! * group commit ✅
+//! * split_into for parallel iteration ✅
+//! * sharded tree ✅
+//! * binary serialization ✅
+//! * serde (feature `serde`) ✅
+//! * write-ahead log ✅
+//! * incremental checkpoint ✅
+//! * sstable export ✅
+//! * merged iter with tombstones ✅
+//! * range aggregates ✅
+//! * interval overlap queries ✅
+//! * persistent BTreeSet ✅
+//! * set algebra between trees ✅
+//! * multimap ✅
+//! * custom key comparators ✅
+//! * prefix iteration ✅
+//! * prefix-compressed leaves for byte keys ✅
+//! * cursor ✅
 //!
 //! Licensed under either of
 //! * Apache License, Version 2.0,
-//! (./LICENSE-APACHE or <http://www.apache.org/licenses/LICENSE-2.0>)
+//!   (./LICENSE-APACHE or <http://www.apache.org/licenses/LICENSE-2.0>)
 //! * MIT license (./LICENSE-MIT or <http://opensource.org/licenses/MIT>)
+//!
 //! at your option.
 //!
 //! ## Examples
@@ -31,38 +52,120 @@
 //! ```
 //!
 
+mod aggregate;
+mod algebra;
 mod batch_write;
+mod checkpoint;
+mod codec;
+mod compact;
+mod comparator;
+mod cursor;
+mod group_commit;
+mod interval;
 mod leaf;
+mod merged;
+mod multimap;
 mod node;
+mod prefix;
+#[cfg(feature = "serde")]
+mod serde_impl;
+#[cfg(feature = "serde")]
+pub mod serde_ttl;
+mod set;
+mod sharded;
+mod shared;
+mod sstable;
+mod wal;
 
 use std::{
-    collections::{BTreeMap, LinkedList},
+    cmp::Ordering,
+    collections::LinkedList,
     fmt::Debug,
-    ops::Add,
+    io::{self, Read, Write},
+    ops::{Add, Bound, RangeBounds},
     sync::Arc,
     time::Duration,
 };
 
-use batch_write::Action;
+use batch_write::{Action, Actions, WriteContext};
+
+pub use aggregate::Aggregate;
+pub use batch_write::{MergeOperator, WriteError, WriteOutcome};
+pub use codec::Codec;
+pub use comparator::{Comparator, OrdComparator, ReverseComparator};
 use leaf::Leaf;
+pub use merged::Value;
 use node::Node;
 
-type N<K, V> = Arc<BTreeType<K, V>>;
+type N<K, V, A = (), C = OrdComparator> = Arc<BTreeType<K, V, A, C>>;
 
 pub type Item<K, V> = Arc<(K, V, Option<Duration>)>;
 
-pub type BatchWrite<K, V> = batch_write::BatchWrite<K, V>;
+pub type BatchWrite<K, V, C = OrdComparator> = batch_write::BatchWrite<K, V, C>;
+
+pub type SharedBTree<K, V> = shared::SharedBTree<K, V>;
+
+pub type GroupCommit<K, V> = group_commit::GroupCommit<K, V>;
+
+pub type ShardedBTree<K, V> = sharded::ShardedBTree<K, V>;
+
+pub type ShardedSnapshot<K, V> = sharded::ShardedSnapshot<K, V>;
+
+pub type ShardedIter<K, V> = sharded::ShardedIter<K, V>;
+
+pub type Wal<K, V> = wal::Wal<K, V>;
+
+pub type Checkpoint<K, V> = checkpoint::Checkpoint<K, V>;
+
+pub type SsTable<K, V> = sstable::SsTable<K, V>;
+
+pub type SsIter<'a, K, V> = sstable::SsIter<'a, K, V>;
+
+pub type MergedIter<K, V> = merged::MergedIter<K, V>;
+
+pub type IntervalTree<K, V> = interval::IntervalTree<K, V>;
+
+pub type Overlapping<K, V> = interval::Overlapping<K, V>;
+
+pub type MaxEnd<K> = interval::MaxEnd<K>;
+
+pub type BTreeSet<K> = set::BTreeSet<K>;
 
-pub type PutResult<K, V> = (Vec<N<K, V>>, Option<Item<K, V>>);
+pub type SetIter<'a, K> = set::SetIter<'a, K>;
 
-pub enum BTreeType<K, V> {
-    Leaf(Leaf<K, V>),
-    Node(Node<K, V>),
+pub type MultiMap<K, V> = multimap::MultiMap<K, V>;
+
+pub type MultiKey<K> = multimap::MultiKey<K>;
+
+pub type MultiValues<K, V> = multimap::MultiValues<K, V>;
+
+pub type MultiGroups<K, V> = multimap::MultiGroups<K, V>;
+
+pub type Prefix<K, V, A = ()> = prefix::Prefix<K, V, A>;
+
+pub type CompactBTree<V> = compact::CompactBTree<V>;
+
+pub type CompactIter<V> = compact::CompactIter<V>;
+
+pub type Cursor<K, V, A = (), C = OrdComparator> = cursor::Cursor<K, V, A, C>;
+
+pub type CursorMut<'a, K, V, A = (), C = OrdComparator> = cursor::CursorMut<'a, K, V, A, C>;
+
+pub type PutResult<K, V, A = (), C = OrdComparator> = (Vec<N<K, V, A, C>>, Option<Item<K, V>>);
+
+pub type RemoveResult<K, V, A = (), C = OrdComparator> = Option<(N<K, V, A, C>, Item<K, V>)>;
+
+pub type SplitResult<K, V, A = (), C = OrdComparator> = (N<K, V, A, C>, N<K, V, A, C>);
+
+pub enum BTreeType<K, V, A = (), C = OrdComparator> {
+    Leaf(Leaf<K, V, A, C>),
+    Node(Node<K, V, A, C>),
 }
 
-impl<K, V> BTreeType<K, V>
+impl<K, V, A, C> BTreeType<K, V, A, C>
 where
-    K: Ord,
+    A: Aggregate<K, V>,
+    C: Comparator<K>,
 {
     /// return min key for this node
     fn key(&self) -> Option<&Item<K, V>> {
@@ -80,38 +183,181 @@ where
         }
     }
 
-    fn put(&self, m: usize, k: K, v: V, ttl: Option<Duration>) -> PutResult<K, V> {
+    fn put(&self, m: usize, k: K, v: V, ttl: Option<Duration>) -> PutResult<K, V, A, C> {
         match self {
             BTreeType::Leaf(leaf) => leaf.put(m, k, v, ttl),
             BTreeType::Node(node) => node.put(m, k, v, ttl),
         }
     }
 
-    fn get(&self, k: &K) -> Option<&V> {
+    fn merge(
+        &self,
+        m: usize,
+        k: K,
+        operand: V,
+        merge_operator: &dyn MergeOperator<K, V>,
+    ) -> PutResult<K, V, A, C> {
+        match self {
+            BTreeType::Leaf(leaf) => leaf.merge(m, k, operand, merge_operator),
+            BTreeType::Node(node) => node.merge(m, k, operand, merge_operator),
+        }
+    }
+
+    fn get(&self, k: &K) -> Option<&Item<K, V>> {
         match self {
             BTreeType::Leaf(leaf) => leaf.get(k),
             BTreeType::Node(node) => node.get(k),
         }
     }
 
-    fn remove(&self, k: &K) -> Option<(N<K, V>, Item<K, V>)> {
+    fn remove(&self, k: &K) -> RemoveResult<K, V, A, C> {
         match self {
             BTreeType::Leaf(leaf) => leaf.remove(k),
             BTreeType::Node(node) => node.remove(k),
         }
     }
 
-    fn write(&self, m: usize, batch_write: BTreeMap<K, Action<V>>) -> Vec<N<K, V>> {
+    fn write(
+        &self,
+        m: usize,
+        batch_write: Actions<K, V>,
+        ctx: &WriteContext<K, V, C>,
+        outcomes: Option<&mut Vec<WriteOutcome<K, V>>>,
+    ) -> Vec<N<K, V, A, C>> {
+        match self {
+            BTreeType::Leaf(leaf) => leaf.write(m, batch_write, ctx, outcomes),
+            BTreeType::Node(node) => node.write(m, batch_write, ctx, outcomes),
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    fn par_write(
+        &self,
+        m: usize,
+        batch_write: Actions<K, V>,
+        ctx: &WriteContext<K, V, C>,
+        threads: usize,
+    ) -> Vec<N<K, V, A, C>>
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+        A: Send + Sync,
+    {
+        match self {
+            BTreeType::Leaf(leaf) => leaf.write(m, batch_write, ctx, None),
+            BTreeType::Node(node) => node.par_write(m, batch_write, ctx, threads),
+        }
+    }
+
+    fn split_off(&self, k: &K, inclusive: bool) -> SplitResult<K, V, A, C> {
+        match self {
+            BTreeType::Leaf(leaf) => leaf.split_off(k, inclusive),
+            BTreeType::Node(node) => node.split_off(k, inclusive),
+        }
+    }
+
+    /// split into the keys before `bound` and the keys from `bound` on
+    fn split_bound(self: &Arc<Self>, bound: Bound<&K>) -> SplitResult<K, V, A, C> {
+        let (left, right) = match bound {
+            Bound::Included(k) => self.split_off(k, true),
+            Bound::Excluded(k) => self.split_off(k, false),
+            Bound::Unbounded => return (Leaf::instance(Vec::new()), self.clone()),
+        };
+        (Self::trim(left), Self::trim(right))
+    }
+
+    /// remove the levels which have only one child on top of node,
+    /// an empty node becomes an empty leaf
+    fn trim(mut node: N<K, V, A, C>) -> N<K, V, A, C> {
+        while let BTreeType::Node(n) = &*node {
+            let child = match n.children.len() {
+                0 => return Leaf::instance(Vec::new()),
+                1 => n.children[0].clone(),
+                _ => break,
+            };
+            node = child;
+        }
+        node
+    }
+
+    /// the number of keys for which before is true, it is true for the keys up to some key
+    fn rank(&self, before: &dyn Fn(&K) -> bool) -> usize {
         match self {
-            BTreeType::Leaf(leaf) => leaf.write(m, batch_write),
-            BTreeType::Node(node) => node.write(m, batch_write),
+            BTreeType::Leaf(leaf) => leaf.items.partition_point(|i| before(&i.0)),
+            BTreeType::Node(n) => {
+                // the children before the last one starting before are all before
+                let i = n
+                    .children
+                    .partition_point(|c| matches!(c.key(), Some(k) if before(&k.0)));
+                if i == 0 {
+                    return 0;
+                }
+                let skipped: usize = n.children[..i - 1].iter().map(|c| c.len()).sum();
+                skipped + n.children[i - 1].rank(before)
+            }
         }
     }
 
-    fn split_off(&self, k: &K) -> (N<K, V>, N<K, V>) {
+    /// the number of node levels above the leaves
+    fn height(&self) -> usize {
         match self {
-            BTreeType::Leaf(leaf) => leaf.split_off(k),
-            BTreeType::Node(node) => node.split_off(k),
+            BTreeType::Leaf(_) => 0,
+            BTreeType::Node(node) => node.children.first().map_or(0, |c| c.height()) + 1,
+        }
+    }
+
+    /// concat two trees, all keys in left must be less than the keys in right
+    fn concat(m: usize, left: N<K, V, A, C>, right: N<K, V, A, C>) -> N<K, V, A, C> {
+        if left.is_empty() {
+            return right;
+        }
+        if right.is_empty() {
+            return left;
+        }
+
+        let nodes = Self::join(m, &left, &right);
+        if nodes.len() > 1 {
+            Node::instance(nodes)
+        } else {
+            nodes[0].clone()
+        }
+    }
+
+    /// join left and right at the height of the higher one,
+    /// the lower one is hung on the nearest spine of the higher one
+    fn join(m: usize, left: &N<K, V, A, C>, right: &N<K, V, A, C>) -> Vec<N<K, V, A, C>> {
+        match (&**left, &**right, left.height().cmp(&right.height())) {
+            (BTreeType::Leaf(l), BTreeType::Leaf(r), _) => {
+                if l.items.len() + r.items.len() > m {
+                    return vec![left.clone(), right.clone()];
+                }
+                let mut items = Vec::with_capacity(l.items.len() + r.items.len());
+                items.extend_from_slice(&l.items);
+                items.extend_from_slice(&r.items);
+                vec![Leaf::instance(items)]
+            }
+            (BTreeType::Node(l), BTreeType::Node(r), Ordering::Equal) => {
+                if l.children.len() + r.children.len() >= m {
+                    return vec![left.clone(), right.clone()];
+                }
+                let mut children = Vec::with_capacity(l.children.len() + r.children.len());
+                children.extend_from_slice(&l.children);
+                children.extend_from_slice(&r.children);
+                vec![Node::instance(children)]
+            }
+            (BTreeType::Node(l), _, Ordering::Greater) => {
+                let (last, rest) = l.children.split_last().unwrap();
+                let mut children = rest.to_vec();
+                children.extend(Self::join(m, last, right));
+                Node::split(m, children)
+            }
+            (_, BTreeType::Node(r), Ordering::Less) => {
+                let (first, rest) = r.children.split_first().unwrap();
+                let mut children = Self::join(m, left, first);
+                children.extend_from_slice(rest);
+                Node::split(m, children)
+            }
+            _ => unreachable!(),
         }
     }
 
@@ -122,6 +368,14 @@ where
         }
     }
 
+    /// the aggregate of all items under this node
+    pub fn aggregate(&self) -> &A {
+        match self {
+            BTreeType::Leaf(leaf) => &leaf.aggregate,
+            BTreeType::Node(node) => &node.aggregate,
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
@@ -133,7 +387,7 @@ where
         }
     }
 
-    fn get_node_by_index(&self, index: usize) -> N<K, V> {
+    fn get_node_by_index(&self, index: usize) -> N<K, V, A, C> {
         if let BTreeType::Node(node) = self {
             node.children[index].clone()
         } else {
@@ -148,7 +402,7 @@ where
         }
     }
 
-    fn expir(self: &Arc<Self>) -> N<K, V> {
+    fn expir(self: &Arc<Self>) -> N<K, V, A, C> {
         match &**self {
             BTreeType::Leaf(leaf) => {
                 if let Some(v) = leaf.expir() {
@@ -166,15 +420,14 @@ where
     }
 }
 
-pub struct Iter<K, V>
-where
-    K: Ord,
-{
-    inner: BTree<K, V>,
-    stack: LinkedList<(N<K, V>, i32)>,
+pub struct Iter<K, V, A = (), C = OrdComparator> {
+    inner: BTree<K, V, A, C>,
+    stack: LinkedList<(N<K, V, A, C>, i32)>,
+    /// next stops before this item, the first item of the next part
+    end: Option<Item<K, V>>,
 }
 
-impl<K: Ord, V> Iterator for Iter<K, V> {
+impl<K, V, A: Aggregate<K, V>, C: Comparator<K>> Iterator for Iter<K, V, A, C> {
     type Item = Item<K, V>;
 
     #[inline]
@@ -183,14 +436,19 @@ impl<K: Ord, V> Iterator for Iter<K, V> {
     }
 }
 
-impl<K, V> Iter<K, V>
+impl<K, V, A, C> Iter<K, V, A, C>
 where
-    K: Ord,
+    A: Aggregate<K, V>,
+    C: Comparator<K>,
 {
-    fn new(inner: BTree<K, V>) -> Self {
+    fn new(inner: BTree<K, V, A, C>) -> Self {
         let mut stack = LinkedList::new();
         stack.push_back((inner.root.clone(), -1));
-        Self { inner, stack }
+        Self {
+            inner,
+            stack,
+            end: None,
+        }
     }
 
     /// # Example
@@ -218,8 +476,13 @@ where
 
             match &*b {
                 BTreeType::Leaf(l) => {
-                    let result = Some(l.items[index as usize].clone());
-                    return result;
+                    let item = &l.items[index as usize];
+                    if matches!(&self.end, Some(end) if Arc::ptr_eq(end, item)) {
+                        // stay before the end item
+                        self.stack.back_mut().unwrap().1 = index - 1;
+                        return None;
+                    }
+                    return Some(item.clone());
                 }
                 BTreeType::Node(n) => {
                     self.stack
@@ -267,6 +530,30 @@ where
         }
     }
 
+    /// seek to the item at the index in key order, next returns it
+    fn seek_index(&mut self, mut index: usize) {
+        self.stack.clear();
+
+        let mut node = self.inner.root.clone();
+        loop {
+            match &*node {
+                BTreeType::Leaf(_) => {
+                    self.stack.push_back((node.clone(), index as i32 - 1));
+                    break;
+                }
+                BTreeType::Node(n) => {
+                    let mut i = 0;
+                    while i + 1 < n.children.len() && index >= n.children[i].len() {
+                        index -= n.children[i].len();
+                        i += 1;
+                    }
+                    self.stack.push_back((node.clone(), i as i32));
+                    node = node.get_node_by_index(i);
+                }
+            }
+        }
+    }
+
     /// clear stack and push root node
     /// it same as new Iter
     pub fn reset(&mut self) {
@@ -355,39 +642,119 @@ fn now() -> Duration {
         .unwrap()
 }
 
-#[derive(Clone)]
-pub struct BTree<K, V> {
+pub struct BTree<K, V, A = (), C = OrdComparator> {
     m: usize,
-    root: N<K, V>,
+    root: N<K, V, A, C>,
+    merge_operator: Option<Arc<dyn MergeOperator<K, V>>>,
 }
 
-impl<K, V> BTree<K, V>
+/// clone is a snapshot, it only clones the root `Arc`
+impl<K, V, A, C> Clone for BTree<K, V, A, C> {
+    fn clone(&self) -> Self {
+        Self {
+            m: self.m,
+            root: self.root.clone(),
+            merge_operator: self.merge_operator.clone(),
+        }
+    }
+}
+
+impl<K, V, C> BTree<K, V, (), C>
 where
-    K: Ord,
+    C: Comparator<K>,
 {
-    /// Create a new BTree with a given branching factor
-    /// The branching factor is the maximum number of children a node can have
-    /// The branching factor must be at least 2
-    /// # Examples
-    /// ```rust
-    /// let mut btree = mem_btree::BTree::new(4);
-    /// let datas = vec![1,2,3,4,5] ;
-    /// for i in datas.iter() {
-    ///    btree.put(i.clone(), i.clone());
-    /// }
-    /// println!("{:?}", btree.len());
-    /// for i in datas.iter() {
-    ///   btree.remove(i);
-    /// }
-    /// println!("{:?}", btree.len());
-    /// ```
+    /// Create a new BTree ordering its keys by the comparator `C`
+    pub fn with_comparator(m: usize) -> Self {
+        Self::with_aggregate(m)
+    }
+
+    /// Build a tree ordered by `C` from items sorted by `C`, like `bulk_load`
     ///
+    /// # Panics
+    /// Panics if the keys are not strictly increasing in the order of `C`
+    pub fn bulk_load_with_comparator<I>(m: usize, items: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V, Option<Duration>)>,
+    {
+        let mut sorted: Vec<Item<K, V>> = Vec::new();
+        for item in items {
+            if let Some(last) = sorted.last() {
+                assert!(
+                    C::cmp(&last.0, &item.0).is_lt(),
+                    "bulk_load keys are not strictly increasing"
+                );
+            }
+            sorted.push(Arc::new(item));
+        }
+        Self::from_sorted_items(m, sorted)
+    }
 
-    // println!("{:?}", btree);
-    pub fn new(m: usize) -> Self {
+    /// Read a tree ordered by `C` written by `write_to`, like `read_from`
+    pub fn read_with_comparator<R: Read>(mut r: R) -> io::Result<Self>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != codec::MAGIC {
+            return Err(codec::invalid("not a serialized btree"));
+        }
+        let version = u8::decode(&mut r)?;
+        if version != codec::VERSION {
+            return Err(codec::invalid(format!("unknown version {version}")));
+        }
+        let m = usize::decode(&mut r)?;
+        if m < 2 {
+            return Err(codec::invalid(format!("bad m {m}")));
+        }
+        let len = usize::decode(&mut r)?;
+
+        let mut items: Vec<Item<K, V>> = Vec::with_capacity(len.min(1 << 16));
+        for _ in 0..len {
+            let ttl = Option::<Duration>::decode(&mut r)?;
+            let k = K::decode(&mut r)?;
+            let v = V::decode(&mut r)?;
+            if matches!(items.last(), Some(last) if C::cmp(&last.0, &k).is_ge()) {
+                return Err(codec::invalid("keys are not strictly increasing"));
+            }
+            items.push(Arc::new((k, v, ttl)));
+        }
+
+        Ok(Self::from_sorted_items(m, items))
+    }
+}
+
+impl<K, V, A, C> BTree<K, V, A, C>
+where
+    A: Aggregate<K, V>,
+    C: Comparator<K>,
+{
+    /// Create a new BTree maintaining the aggregate `A` in every node,
+    /// query it over a range by `aggregate`
+    pub fn with_aggregate(m: usize) -> Self {
         Self {
             m,
-            root: Arc::new(BTreeType::Leaf(Leaf { items: Vec::new() })),
+            root: Leaf::instance(Vec::new()),
+            merge_operator: None,
+        }
+    }
+
+    /// Set the merge operator used by `merge` and the merge actions of batches
+    pub fn with_merge_operator<O>(mut self, merge_operator: O) -> Self
+    where
+        O: MergeOperator<K, V> + 'static,
+    {
+        self.merge_operator = Some(Arc::new(merge_operator));
+        self
+    }
+
+    /// a tree with the same settings as self holding the given root
+    fn with_root(&self, root: N<K, V, A, C>) -> Self {
+        BTree {
+            m: self.m,
+            root,
+            merge_operator: self.merge_operator.clone(),
         }
     }
 
@@ -407,6 +774,72 @@ where
         self.inner_put(k, v, Some(ttl))
     }
 
+    /// Insert a key-value pair only if the key is not in the B-tree
+    /// If the key already exists, the tree is unchanged and the existing item is returned
+    /// If the key does not exist, the pair is inserted and None is returned
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let mut btree = BTree::new(32);
+    /// assert_eq!(btree.put_if_absent(1, "a"), None);
+    /// assert_eq!(btree.put_if_absent(1, "b").map(|i| i.1), Some("a"));
+    /// assert_eq!(btree.get(&1), Some(&"a"));
+    /// ```
+    pub fn put_if_absent(&mut self, k: K, v: V) -> Option<Item<K, V>> {
+        if let Some(item) = self.get_item(&k) {
+            return Some(item.clone());
+        }
+        self.inner_put(k, v, None)
+    }
+
+    /// Replace the value of a key only if the current value equals `expected`
+    /// The ttl of the existing item is kept
+    /// If the value was replaced, the old item is returned
+    /// If the key does not exist or the value does not match, None is returned
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let mut btree = BTree::new(32);
+    /// btree.put(1, "a");
+    /// assert_eq!(btree.replace_if(1, &"b", "c"), None);
+    /// assert_eq!(btree.replace_if(1, &"a", "c").map(|i| i.1), Some("a"));
+    /// assert_eq!(btree.get(&1), Some(&"c"));
+    /// ```
+    pub fn replace_if(&mut self, k: K, expected: &V, v: V) -> Option<Item<K, V>>
+    where
+        V: PartialEq,
+    {
+        match self.get_item(&k) {
+            Some(item) if item.1 == *expected => {
+                let ttl = item.2;
+                self.inner_put(k, v, ttl)
+            }
+            _ => None,
+        }
+    }
+
+    /// Remove a key only if the predicate holds for its current value
+    /// If the key was removed, the old item is returned
+    /// If the key does not exist or the predicate is false, None is returned
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let mut btree = BTree::new(32);
+    /// btree.put(1, 10);
+    /// assert_eq!(btree.remove_if(&1, |v| *v > 10), None);
+    /// assert_eq!(btree.remove_if(&1, |v| *v == 10).map(|i| i.1), Some(10));
+    /// assert!(btree.is_empty());
+    /// ```
+    pub fn remove_if<F>(&mut self, k: &K, f: F) -> Option<Item<K, V>>
+    where
+        F: FnOnce(&V) -> bool,
+    {
+        match self.get_item(k) {
+            Some(item) if f(&item.1) => self.remove(k),
+            _ => None,
+        }
+    }
+
     fn inner_put(&mut self, k: K, v: V, ttl: Option<Duration>) -> Option<Item<K, V>> {
         let (values, v) = self.root.put(self.m, k, v, ttl);
         if values.len() > 1 {
@@ -417,6 +850,24 @@ where
         v
     }
 
+    /// Combine the operand with the value of a key by the merge operator,
+    /// in the leaf found by the same descent as a put
+    /// The ttl of the existing item is kept
+    /// If the key already exists, the old value is returned
+    /// If the key does not exist, None is returned
+    ///
+    /// # Panics
+    /// Panics if the tree has no merge operator, before the tree is changed
+    pub fn merge(&mut self, k: K, operand: V) -> Option<Item<K, V>> {
+        let merge_operator = self
+            .merge_operator
+            .clone()
+            .expect("merge operator is not set");
+        let (values, old) = self.root.merge(self.m, k, operand, merge_operator.as_ref());
+        self.set_root(values);
+        old
+    }
+
     /// Remove a key-value pair from the B-tree
     /// If the key exists, the old value is returned
     /// If the key does not exist, None is returned
@@ -432,8 +883,28 @@ where
         Some(item)
     }
 
+    /// make the root from the nodes a write returns
+    fn set_root(&mut self, mut nodes: Vec<N<K, V, A, C>>) {
+        while nodes.len() > self.m {
+            nodes = Node::chunks(self.m, nodes);
+        }
+
+        if nodes.len() > 1 {
+            self.root = Node::instance(nodes);
+        } else {
+            match nodes.into_iter().next() {
+                Some(v) => self.root = v,
+                None => self.root = Leaf::instance(Vec::with_capacity(self.m)),
+            };
+        }
+    }
+
     /// Write a batch of key-value pairs into the B-tree
     ///
+    /// # Panics
+    /// Panics if the batch has merge actions and the tree has no merge operator,
+    /// the batch is checked before anything is written, `try_write` returns the error
+    ///
     /// # Examples
     /// ```
     /// use mem_btree::BTree;
@@ -446,30 +917,127 @@ where
     /// btree.write(bw);
     /// ```
     ///
-    pub fn write(&mut self, batch_write: BatchWrite<K, V>) {
-        let mut nodes = self.root.write(self.m, batch_write.into_map());
+    pub fn write(&mut self, batch_write: BatchWrite<K, V, C>) {
+        if let Err(e) = self.try_write(batch_write) {
+            panic!("{e}");
+        }
+    }
 
-        while nodes.len() > self.m {
-            nodes = nodes
-                .chunks(self.m)
-                .filter_map(|c| {
-                    if c.is_empty() {
-                        None
-                    } else {
-                        Some(Node::instance(c.to_vec()))
-                    }
-                })
-                .collect();
+    /// Write a batch of key-value pairs into the B-tree like `write`,
+    /// a batch the tree cannot apply is an error and nothing of it is written
+    ///
+    /// # Examples
+    /// ```
+    /// use mem_btree::{BTree, BatchWrite, WriteError};
+    /// let mut btree = BTree::new(32);
+    /// let mut bw = BatchWrite::default();
+    /// bw.put(1, 1);
+    /// bw.merge(2, 2);
+    /// assert_eq!(btree.try_write(bw), Err(WriteError::NoMergeOperator));
+    /// assert!(btree.is_empty());
+    /// ```
+    pub fn try_write(&mut self, batch_write: BatchWrite<K, V, C>) -> Result<(), WriteError> {
+        self.check_write(&batch_write)?;
+        self.inner_write(batch_write, None);
+        Ok(())
+    }
+
+    /// check that every action of the batch can be applied to this tree
+    pub(crate) fn check_write(&self, batch_write: &BatchWrite<K, V, C>) -> Result<(), WriteError> {
+        if self.merge_operator.is_none() && batch_write.needs_merge_operator() {
+            return Err(WriteError::NoMergeOperator);
         }
+        Ok(())
+    }
 
-        if nodes.len() > 1 {
-            self.root = Node::instance(nodes);
-        } else {
-            match nodes.into_iter().next() {
-                Some(v) => self.root = v,
-                None => self.root = Leaf::instance(Vec::with_capacity(self.m)),
-            };
+    /// Write a batch of key-value pairs into the B-tree,
+    /// and return what the batch did to every key, in key order
+    ///
+    /// # Panics
+    /// Panics like `write`, before anything is written
+    ///
+    /// # Examples
+    /// ```
+    /// use mem_btree::{BTree, BatchWrite, WriteOutcome};
+    /// let mut btree = BTree::new(32);
+    /// btree.put(1, 1);
+    /// btree.put(2, 2);
+    /// let mut bw = BatchWrite::default();
+    /// bw.put(1, 10);
+    /// bw.delete(2);
+    /// bw.delete(3);
+    /// bw.put(4, 4);
+    /// let outcomes = btree.write_with_outcomes(bw);
+    /// assert!(matches!(&outcomes[0], WriteOutcome::Replaced(old) if old.1 == 1));
+    /// assert!(matches!(&outcomes[1], WriteOutcome::Deleted(old) if old.1 == 2));
+    /// assert!(matches!(&outcomes[2], WriteOutcome::DeleteMiss(3)));
+    /// assert!(matches!(&outcomes[3], WriteOutcome::Inserted(new) if new.1 == 4));
+    /// ```
+    pub fn write_with_outcomes(
+        &mut self,
+        batch_write: BatchWrite<K, V, C>,
+    ) -> Vec<WriteOutcome<K, V>> {
+        if let Err(e) = self.check_write(&batch_write) {
+            panic!("{e}");
+        }
+        let mut outcomes = Vec::new();
+        self.inner_write(batch_write, Some(&mut outcomes));
+        outcomes
+    }
+
+    fn inner_write(
+        &mut self,
+        batch_write: BatchWrite<K, V, C>,
+        outcomes: Option<&mut Vec<WriteOutcome<K, V>>>,
+    ) {
+        let (actions, ranges) = batch_write.into_parts();
+        let ctx = WriteContext {
+            ranges: &ranges,
+            merge_operator: self.merge_operator.as_deref(),
+        };
+        let nodes = self.root.write(self.m, actions, &ctx, outcomes);
+        self.set_root(nodes);
+    }
+
+    /// Write a batch of key-value pairs into the B-tree like `write`,
+    /// the parts of the batch for different subtrees are written at the same
+    /// time by as many threads as `std::thread::available_parallelism`,
+    /// it pays off for very large batches
+    ///
+    /// # Panics
+    /// Panics like `write`, before anything is written
+    ///
+    /// # Examples
+    /// ```
+    /// use mem_btree::BTree;
+    /// use mem_btree::BatchWrite;
+    /// let mut btree = BTree::new(32);
+    /// let mut bw = BatchWrite::default();
+    /// for i in 0..100_000 {
+    ///     bw.put(i, i);
+    /// }
+    /// btree.par_write(bw);
+    /// assert_eq!(btree.len(), 100_000);
+    /// ```
+    #[cfg(feature = "parallel")]
+    pub fn par_write(&mut self, batch_write: BatchWrite<K, V, C>)
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+        A: Send + Sync,
+    {
+        if let Err(e) = self.check_write(&batch_write) {
+            panic!("{e}");
         }
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+        let (actions, ranges) = batch_write.into_parts();
+        let ctx = WriteContext {
+            ranges: &ranges,
+            merge_operator: self.merge_operator.as_deref(),
+        };
+        let nodes = self.root.par_write(self.m, actions, &ctx, threads);
+        self.set_root(nodes);
     }
 
     /// Split off a part of the B-tree
@@ -489,14 +1057,60 @@ where
     /// assert_eq!(right.len(), 3); // 3,4,5
     /// ```
     ///
-    pub fn split_off(&mut self, k: &K) -> BTree<K, V> {
-        let (left, right) = self.root.split_off(k);
+    pub fn split_off(&mut self, k: &K) -> BTree<K, V, A, C> {
+        let (left, right) = self.root.split_bound(Bound::Included(k));
         self.root = left;
 
-        BTree {
-            m: self.m,
-            root: right,
-        }
+        self.with_root(right)
+    }
+
+    /// Remove all keys in the range from the B-tree
+    /// The tree is cut twice like split_off and the two sides are joined again,
+    /// so it costs O(log n) node operations however large the range is
+    /// Return the number of removed keys
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let mut btree = BTree::new(32);
+    /// for i in 0..100 {
+    ///     btree.put(i, i);
+    /// }
+    /// assert_eq!(btree.remove_range(10..90), 80);
+    /// assert_eq!(btree.len(), 20);
+    /// assert_eq!(btree.get(&9), Some(&9));
+    /// assert_eq!(btree.get(&10), None);
+    /// assert_eq!(btree.get(&90), Some(&90));
+    /// ```
+    pub fn remove_range<R: RangeBounds<K>>(&mut self, range: R) -> usize {
+        self.extract_range(range).len()
+    }
+
+    /// Move all keys in the range out of the B-tree into a new B-tree
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let mut btree = BTree::new(32);
+    /// for i in 0..100 {
+    ///     btree.put(i, i);
+    /// }
+    /// let middle = btree.extract_range(10..=89);
+    /// assert_eq!(btree.len(), 20);
+    /// assert_eq!(middle.len(), 80);
+    /// assert_eq!(middle.get(&10), Some(&10));
+    /// assert_eq!(middle.get(&90), None);
+    /// ```
+    pub fn extract_range<R: RangeBounds<K>>(&mut self, range: R) -> BTree<K, V, A, C> {
+        let (left, rest) = self.root.split_bound(range.start_bound());
+
+        let (middle, right) = match range.end_bound() {
+            Bound::Included(k) => rest.split_bound(Bound::Excluded(k)),
+            Bound::Excluded(k) => rest.split_bound(Bound::Included(k)),
+            Bound::Unbounded => (rest, Leaf::instance(Vec::new())),
+        };
+
+        self.root = BTreeType::concat(self.m, left, right);
+
+        self.with_root(middle)
     }
 
     /// Get the value for a given key
@@ -514,7 +1128,11 @@ where
     /// assert_eq!(btree.get(&6), None);
     /// ```
     pub fn get(&self, k: &K) -> Option<&V> {
-        if self.root.len() == 0 {
+        self.get_item(k).map(|item| &item.1)
+    }
+
+    pub(crate) fn get_item(&self, k: &K) -> Option<&Item<K, V>> {
+        if self.root.is_empty() {
             return None;
         }
         self.root.get(k)
@@ -541,13 +1159,105 @@ where
 
     /// make a Iter for this btree
     /// default is seek_first
-    pub fn iter(&self) -> Iter<K, V> {
-        Iter::new(Self {
-            m: self.m,
-            root: self.root.clone(),
+    pub fn iter(&self) -> Iter<K, V, A, C> {
+        Iter::new(self.with_root(self.root.clone()))
+    }
+
+    /// Split the tree into at most n iterators over consecutive key ranges
+    /// of about the same number of items, found by the cached subtree lengths.
+    /// Each iterator stops before the first item of the next one,
+    /// they can be sent to different threads
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let mut btree = BTree::new(32);
+    /// for i in 0..1000 {
+    ///     btree.put(i, i);
+    /// }
+    /// let parts = btree.split_into(4);
+    /// let handles: Vec<_> = parts
+    ///     .into_iter()
+    ///     .map(|iter| std::thread::spawn(move || iter.map(|item| item.1).sum::<i32>()))
+    ///     .collect();
+    /// let sum: i32 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    /// assert_eq!(sum, (0..1000).sum());
+    /// ```
+    pub fn split_into(&self, n: usize) -> Vec<Iter<K, V, A, C>> {
+        let len = self.len();
+        let n = n.clamp(1, len.max(1));
+
+        (0..n)
+            .map(|i| {
+                let mut iter = self.iter();
+                iter.seek_index(len * i / n);
+                iter.end = self.get_index(len * (i + 1) / n).cloned();
+                iter
+            })
+            .collect()
+    }
+
+    /// Fold the items by as many threads as `std::thread::available_parallelism`,
+    /// each thread folds one part of `split_into` from `identity()`,
+    /// and the results of the parts are combined by reduce in key order
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let mut btree = BTree::new(32);
+    /// for i in 0..1000u64 {
+    ///     btree.put(i, i);
+    /// }
+    /// let sum = btree.par_fold(|| 0, |acc, item| acc + item.1, |a, b| a + b);
+    /// assert_eq!(sum, (0..1000).sum());
+    /// ```
+    #[cfg(feature = "parallel")]
+    pub fn par_fold<T, I, F, R>(&self, identity: I, fold: F, reduce: R) -> T
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+        A: Send + Sync,
+        T: Send,
+        I: Fn() -> T + Sync,
+        F: Fn(T, Item<K, V>) -> T + Sync,
+        R: Fn(T, T) -> T,
+    {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let parts = self.split_into(threads);
+
+        std::thread::scope(|s| {
+            let handles: Vec<_> = parts
+                .into_iter()
+                .map(|iter| s.spawn(|| iter.fold(identity(), &fold)))
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+                .fold(identity(), reduce)
         })
     }
 
+    /// the item at the index in key order
+    fn get_index(&self, mut index: usize) -> Option<&Item<K, V>> {
+        if index >= self.len() {
+            return None;
+        }
+
+        let mut node = &self.root;
+        loop {
+            match &**node {
+                BTreeType::Leaf(l) => return l.items.get(index),
+                BTreeType::Node(n) => {
+                    let mut i = 0;
+                    while i + 1 < n.children.len() && index >= n.children[i].len() {
+                        index -= n.children[i].len();
+                        i += 1;
+                    }
+                    node = &n.children[i];
+                }
+            }
+        }
+    }
+
     /// Get the minimum key in the B-tree
     pub fn min(&mut self) -> Option<&Item<K, V>> {
         self.root.key()
@@ -561,22 +1271,142 @@ where
     pub fn expir(&self) -> Self {
         let root = self.root.expir();
 
-        BTree { m: self.m, root }
+        self.with_root(root)
+    }
+
+    fn from_sorted_items(m: usize, items: Vec<Item<K, V>>) -> Self {
+        let mut tree = Self::with_aggregate(m);
+        let leaves = items
+            .chunks(m)
+            .map(|c| Leaf::instance(c.to_vec()))
+            .collect();
+        tree.set_root(leaves);
+        tree
+    }
+
+    /// Write the items of this snapshot in a compact binary format.
+    /// It holds a version, `m`, the item count and every item with its ttl deadline,
+    /// the merge operator is not written
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let mut btree = BTree::new(32);
+    /// btree.put(1u32, String::from("a"));
+    /// btree.put(2, String::from("b"));
+    ///
+    /// let mut buf = Vec::new();
+    /// btree.write_to(&mut buf).unwrap();
+    /// let copy: BTree<u32, String> = BTree::read_from(buf.as_slice()).unwrap();
+    /// assert_eq!(copy.get(&2), Some(&String::from("b")));
+    /// ```
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        w.write_all(codec::MAGIC)?;
+        w.write_all(&[codec::VERSION])?;
+        self.m.encode(&mut w)?;
+        self.len().encode(&mut w)?;
+        for item in self.iter() {
+            item.2.encode(&mut w)?;
+            item.0.encode(&mut w)?;
+            item.1.encode(&mut w)?;
+        }
+        w.flush()
+    }
+}
+
+impl<K, V> BTree<K, V>
+where
+    K: Ord,
+{
+    /// Create a new BTree with a given branching factor
+    /// The branching factor is the maximum number of children a node can have
+    /// The branching factor must be at least 2
+    /// # Examples
+    /// ```rust
+    /// let mut btree = mem_btree::BTree::new(4);
+    /// let datas = vec![1,2,3,4,5] ;
+    /// for i in datas.iter() {
+    ///    btree.put(i.clone(), i.clone());
+    /// }
+    /// println!("{:?}", btree.len());
+    /// for i in datas.iter() {
+    ///   btree.remove(i);
+    /// }
+    /// println!("{:?}", btree.len());
+    /// ```
+    pub fn new(m: usize) -> Self {
+        Self::with_aggregate(m)
+    }
+
+    /// Build a tree from items sorted by key in O(n), the leaves are filled one after another
+    ///
+    /// # Panics
+    /// Panics if the keys are not strictly increasing
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let btree = BTree::bulk_load(32, (0..100).map(|i| (i, i * 2, None)));
+    /// assert_eq!(btree.len(), 100);
+    /// assert_eq!(btree.get(&10), Some(&20));
+    /// ```
+    pub fn bulk_load<I>(m: usize, items: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V, Option<Duration>)>,
+    {
+        Self::bulk_load_with_comparator(m, items)
+    }
+
+    /// Read a tree written by `write_to`, the tree is bulk loaded in O(n).
+    /// Data of an unknown version or with keys out of order is `InvalidData`
+    pub fn read_from<R: Read>(r: R) -> io::Result<Self>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        Self::read_with_comparator(r)
+    }
+
+    /// Write the items of this snapshot to a new sorted table file,
+    /// open it as a read-only map by `SsTable::open`
+    pub fn write_sstable<P: AsRef<std::path::Path>>(&self, path: P) -> io::Result<()>
+    where
+        K: Codec + Clone,
+        V: Codec,
+    {
+        sstable::create(self, path)
+    }
+
+    /// Write the nodes of this snapshot that are not in the prev checkpoint,
+    /// the subtrees shared with it are skipped without being visited.
+    /// Load a chain of checkpoints by `Checkpoint::load`
+    pub fn checkpoint<W: Write>(
+        &self,
+        prev: &Checkpoint<K, V>,
+        w: W,
+    ) -> io::Result<Checkpoint<K, V>>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        Checkpoint::write(self, prev, w)
     }
 }
 
-impl<K: Debug + Eq + Ord, V: Debug> Debug for BTree<K, V> {
+impl<K: Debug, V: Debug, A: Aggregate<K, V>, C: Comparator<K>> Debug for BTree<K, V, A, C> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_list().entries(self.iter()).finish()
     }
 }
 
-fn cmp<K, V>(k1: Option<&Item<K, V>>, k2: Option<&K>) -> std::cmp::Ordering
+fn cmp<K, V, C>(k1: Option<&Item<K, V>>, k2: Option<&K>) -> std::cmp::Ordering
 where
-    K: Ord,
+    C: Comparator<K>,
 {
     match (k1, k2) {
-        (Some(k1), Some(k2)) => k1.0.cmp(k2),
+        (Some(k1), Some(k2)) => C::cmp(&k1.0, k2),
         (Some(_), None) => std::cmp::Ordering::Greater,
         (None, Some(_)) => std::cmp::Ordering::Less,
         (None, None) => std::cmp::Ordering::Equal,
@@ -585,7 +1415,7 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::BatchWrite;
+    use crate::{BatchWrite, WriteError, WriteOutcome};
 
     use super::BTree;
     use rand::rngs::StdRng;
@@ -642,10 +1472,9 @@ mod tests {
         }
 
         // Remove some key-value pairs from both data structures
-        for i in 0..5000 {
-            let (key, _) = pairs[i];
-            btree.remove(&key);
-            btree_map.remove(&key);
+        for (key, _) in pairs.iter().take(5000) {
+            btree.remove(key);
+            btree_map.remove(key);
         }
 
         // Check if the values are the same in both data structures
@@ -989,6 +1818,534 @@ mod tests {
         assert_eq!(btree.get(&1), None);
     }
 
+    #[test]
+    fn test_remove_range() {
+        let mut rng = StdRng::seed_from_u64(42);
+
+        for m in [4, 8, 32] {
+            let mut btree = BTree::new(m);
+            let mut btree_map = BTreeMap::new();
+            for _ in 0..2000 {
+                let key = rng.gen_range(0..4000);
+                btree.put(key, key);
+                btree_map.insert(key, key);
+            }
+
+            for _ in 0..200 {
+                let start = rng.gen_range(0..4000);
+                let end = rng.gen_range(start..4000);
+
+                let mut temp = btree.clone();
+                let mut temp_map = btree_map.clone();
+
+                let middle = temp.extract_range(start..end);
+                let removed: Vec<_> = temp_map.range(start..end).map(|(k, _)| *k).collect();
+                for k in removed.iter() {
+                    temp_map.remove(k);
+                }
+
+                assert_eq!(middle.len(), removed.len());
+                assert_eq!(middle.iter().map(|i| i.0).collect::<Vec<_>>(), removed);
+                assert_eq!(temp.len(), temp_map.len());
+                assert_eq!(
+                    temp.iter().map(|i| i.0).collect::<Vec<_>>(),
+                    temp_map.keys().cloned().collect::<Vec<_>>()
+                );
+
+                // the joined tree must stay writable
+                temp.put(start, start);
+                temp_map.insert(start, start);
+                assert_eq!(temp.len(), temp_map.len());
+                for (k, v) in temp_map.iter() {
+                    assert_eq!(temp.get(k), Some(v));
+                }
+            }
+
+            let mut temp = btree.clone();
+            assert_eq!(temp.remove_range(..), btree_map.len());
+            assert!(temp.is_empty());
+            temp.put(1, 1);
+            assert_eq!(temp.len(), 1);
+
+            let mut temp = btree.clone();
+            let n = btree_map.range(1000..=2000).count();
+            assert_eq!(temp.remove_range(1000..=2000), n);
+            assert_eq!(temp.len(), btree_map.len() - n);
+        }
+    }
+
+    #[test]
+    fn test_batch_delete_range() {
+        let mut rng = StdRng::seed_from_u64(42);
+
+        for m in [4, 8, 32] {
+            let mut btree = BTree::new(m);
+            let mut btree_map = BTreeMap::new();
+
+            for _ in 0..500 {
+                let mut bw = BatchWrite::default();
+                for _ in 0..rng.gen_range(1..50) {
+                    let key = rng.gen_range(0..2000);
+                    match rng.gen_range(0..10) {
+                        0 => {
+                            let end = key + rng.gen_range(0..300);
+                            bw.delete_range(key..end);
+                            btree_map.retain(|k, _| !(key..end).contains(k));
+                        }
+                        1 => {
+                            bw.delete(key);
+                            btree_map.remove(&key);
+                        }
+                        _ => {
+                            bw.put(key, key);
+                            btree_map.insert(key, key);
+                        }
+                    }
+                }
+                btree.write(bw);
+
+                assert_eq!(btree.len(), btree_map.len());
+            }
+
+            assert_eq!(
+                btree.iter().map(|i| i.0).collect::<Vec<_>>(),
+                btree_map.keys().cloned().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_batch_delete_range_then_put() {
+        let mut btree = BTree::new(4);
+        for i in 0..100 {
+            btree.put(i, i);
+        }
+
+        let mut bw = BatchWrite::default();
+        bw.put(20, 0);
+        bw.delete_range(10..90);
+        bw.put(50, 1);
+        bw.delete_range(95..200);
+        btree.write(bw);
+
+        assert_eq!(btree.len(), 10 + 1 + 5);
+        assert_eq!(btree.get(&9), Some(&9));
+        assert_eq!(btree.get(&20), None);
+        assert_eq!(btree.get(&50), Some(&1));
+        assert_eq!(btree.get(&94), Some(&94));
+        assert_eq!(btree.get(&95), None);
+    }
+
+    #[test]
+    fn test_batch_write_outcomes() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut btree = BTree::new(8);
+        let mut btree_map = BTreeMap::new();
+
+        for _ in 0..300 {
+            let mut bw = BatchWrite::default();
+            let mut expected = BTreeMap::new();
+            for _ in 0..rng.gen_range(1..40) {
+                let key = rng.gen_range(0..500);
+                if rng.gen_range(0..3) == 0 {
+                    bw.delete(key);
+                    expected.insert(key, None);
+                } else {
+                    let value = rng.gen::<u32>();
+                    bw.put(key, value);
+                    expected.insert(key, Some(value));
+                }
+            }
+            if rng.gen_range(0..5) == 0 {
+                let start = rng.gen_range(0..500);
+                bw.delete_range(start..start + 20);
+                // the actions before are dropped, the existing keys are deleted
+                for k in start..start + 20 {
+                    expected.remove(&k);
+                    if btree_map.contains_key(&k) {
+                        expected.insert(k, None);
+                    }
+                }
+            }
+
+            let outcomes = btree.write_with_outcomes(bw);
+            assert_eq!(outcomes.len(), expected.len());
+
+            for (outcome, (k, v)) in outcomes.iter().zip(expected.iter()) {
+                let old = btree_map.get(k).cloned();
+                match (outcome, v) {
+                    (WriteOutcome::Inserted(new), Some(v)) => {
+                        assert_eq!((new.0, new.1), (*k, *v));
+                        assert_eq!(old, None);
+                    }
+                    (WriteOutcome::Replaced(item), Some(_)) => {
+                        assert_eq!((item.0, Some(item.1)), (*k, old));
+                    }
+                    (WriteOutcome::Deleted(item), None) => {
+                        assert_eq!((item.0, Some(item.1)), (*k, old));
+                    }
+                    (WriteOutcome::DeleteMiss(miss), None) => {
+                        assert_eq!(miss, k);
+                        assert_eq!(old, None);
+                    }
+                    _ => panic!("unexpected outcome {:?} for {}", outcome, k),
+                }
+            }
+
+            for (k, v) in expected {
+                match v {
+                    Some(v) => btree_map.insert(k, v),
+                    None => btree_map.remove(&k),
+                };
+            }
+            assert_eq!(btree.len(), btree_map.len());
+        }
+    }
+
+    #[test]
+    fn test_batch_conditional() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut btree = BTree::new(8);
+        let mut btree_map = BTreeMap::new();
+
+        for _ in 0..500 {
+            let mut bw = BatchWrite::default();
+            let mut model = btree_map.clone();
+            for _ in 0..rng.gen_range(1..40) {
+                let key = rng.gen_range(0..200);
+                let value = rng.gen_range(0..4);
+                match rng.gen_range(0..6) {
+                    0 => {
+                        bw.delete(key);
+                        model.remove(&key);
+                    }
+                    1 => {
+                        bw.put_if_absent(key, value);
+                        model.entry(key).or_insert(value);
+                    }
+                    2 => {
+                        let expected = rng.gen_range(0..4);
+                        bw.compare_and_set(key, expected, value);
+                        if model.get(&key) == Some(&expected) {
+                            model.insert(key, value);
+                        }
+                    }
+                    3 => {
+                        bw.delete_range(key..key + 5);
+                        model.retain(|k, _| !(key..key + 5).contains(k));
+                    }
+                    _ => {
+                        bw.put(key, value);
+                        model.insert(key, value);
+                    }
+                }
+            }
+            btree.write(bw);
+            btree_map = model;
+
+            assert_eq!(
+                btree.iter().map(|i| (i.0, i.1)).collect::<Vec<_>>(),
+                btree_map.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>()
+            );
+        }
+
+        let mut bw = BatchWrite::default();
+        let (absent, present) = (1000, *btree_map.keys().next().unwrap());
+        bw.put_if_absent(present, 9);
+        bw.compare_and_set(absent, 9, 9);
+        let outcomes = btree.write_with_outcomes(bw);
+        assert!(matches!(outcomes[0], WriteOutcome::Rejected(k) if k == present));
+        assert!(matches!(outcomes[1], WriteOutcome::Rejected(k) if k == absent));
+    }
+
+    #[test]
+    fn test_conditional() {
+        let mut btree = BTree::new(4);
+        for i in 0..100 {
+            assert!(btree.put_if_absent(i, i).is_none());
+        }
+        for i in 0..100 {
+            assert_eq!(btree.put_if_absent(i, 0).map(|item| item.1), Some(i));
+        }
+
+        for i in 0..100 {
+            assert!(btree.replace_if(i, &(i + 1), 0).is_none());
+            assert_eq!(btree.replace_if(i, &i, i * 2).map(|item| item.1), Some(i));
+        }
+        assert!(btree.replace_if(100, &0, 0).is_none());
+
+        // a replaced value keeps the ttl of the item
+        btree.put_ttl(200, 1, Duration::from_secs(100));
+        let ttl = btree.get_item(&200).unwrap().2;
+        assert!(ttl.is_some());
+        assert_eq!(btree.replace_if(200, &1, 2).map(|item| item.2), Some(ttl));
+        assert_eq!(
+            btree.get_item(&200).map(|item| (item.1, item.2)),
+            Some((2, ttl))
+        );
+        btree.remove(&200);
+
+        for i in 0..100 {
+            let removed = btree.remove_if(&i, |v| v % 4 == 0);
+            assert_eq!(removed.is_some(), i % 2 == 0);
+        }
+        assert_eq!(btree.len(), 50);
+        assert!(btree.remove_if(&100, |_| true).is_none());
+    }
+
+    #[test]
+    fn test_merge_operator() {
+        let append = |_: &u32, v: Option<&Vec<u32>>, mut op: Vec<u32>| {
+            let mut v = v.cloned().unwrap_or_default();
+            v.append(&mut op);
+            v
+        };
+        let mut btree = BTree::new(4).with_merge_operator(append);
+        let mut btree_map: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+        let mut rng = StdRng::seed_from_u64(42);
+
+        for i in 0..200 {
+            let mut bw = BatchWrite::default();
+            for j in 0..20 {
+                let key = rng.gen_range(0..100);
+                match rng.gen_range(0..5) {
+                    0 => {
+                        bw.put(key, vec![j]);
+                        btree_map.insert(key, vec![j]);
+                    }
+                    1 => {
+                        bw.delete(key);
+                        btree_map.remove(&key);
+                    }
+                    _ => {
+                        bw.merge(key, vec![i, j]);
+                        btree_map.entry(key).or_default().extend([i, j]);
+                    }
+                }
+            }
+            btree.write(bw);
+
+            let key = rng.gen_range(0..100);
+            btree.merge(key, vec![i]);
+            btree_map.entry(key).or_default().push(i);
+        }
+
+        assert_eq!(
+            btree.iter().map(|i| (i.0, i.1.clone())).collect::<Vec<_>>(),
+            btree_map.into_iter().collect::<Vec<_>>()
+        );
+
+        // the snapshot keeps the merge operator
+        let mut snapshot = btree.clone();
+        snapshot.merge(1000, vec![1]);
+        assert_eq!(snapshot.get(&1000), Some(&vec![1]));
+    }
+
+    #[test]
+    fn test_try_write_without_operator() {
+        let mut btree = BTree::new(4);
+        for i in 0..10 {
+            btree.put(i, i);
+        }
+        let mut bw = BatchWrite::default();
+        bw.put(20, 20);
+        bw.put(1, 1);
+        bw.merge(1, 1);
+        assert_eq!(btree.try_write(bw), Err(WriteError::NoMergeOperator));
+        assert_eq!(btree.len(), 10);
+        assert_eq!(btree.get(&20), None);
+
+        let mut bw = BatchWrite::default();
+        bw.put(20, 20);
+        assert_eq!(btree.try_write(bw), Ok(()));
+        assert_eq!(btree.get(&20), Some(&20));
+    }
+
+    #[test]
+    #[should_panic(expected = "merge operator is not set")]
+    fn test_merge_without_operator() {
+        let mut btree = BTree::new(4);
+        btree.put(1, 1);
+        btree.merge(1, 1);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_par_write() {
+        let mut rng = StdRng::seed_from_u64(42);
+
+        for m in [4, 32] {
+            let mut btree = BTree::new(m);
+            let mut par_btree = BTree::new(m);
+
+            for _ in 0..5 {
+                let make = |rng: &mut StdRng| {
+                    let mut bw = BatchWrite::default();
+                    for _ in 0..50000 {
+                        let key = rng.gen_range(0..200000);
+                        match rng.gen_range(0..10) {
+                            0 => bw.delete(key),
+                            1 => bw.delete_range(key..key + 100),
+                            _ => bw.put(key, key),
+                        }
+                    }
+                    bw
+                };
+                let seed = rng.gen::<u64>();
+                btree.write(make(&mut StdRng::seed_from_u64(seed)));
+                par_btree.par_write(make(&mut StdRng::seed_from_u64(seed)));
+
+                assert_eq!(btree.len(), par_btree.len());
+                assert!(btree.iter().map(|i| i.0).eq(par_btree.iter().map(|i| i.0)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_into() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for m in [4, 32] {
+            let mut btree = BTree::new(m);
+            for _ in 0..3000 {
+                let key = rng.gen::<u32>();
+                btree.put(key, key);
+            }
+            btree.remove_range(1000..u32::MAX / 3);
+
+            let all: Vec<_> = btree.iter().map(|i| i.0).collect();
+            for n in [1, 2, 3, 7, 16, 5000] {
+                let parts = btree.split_into(n);
+                assert_eq!(parts.len(), n.min(all.len()));
+
+                let parts: Vec<Vec<_>> = parts
+                    .into_iter()
+                    .map(|iter| iter.map(|i| i.0).collect())
+                    .collect();
+                let sizes: Vec<_> = parts.iter().map(|p| p.len()).collect();
+                assert!(sizes.iter().max().unwrap() - sizes.iter().min().unwrap() <= 1);
+                assert_eq!(parts.concat(), all);
+            }
+        }
+
+        let btree: BTree<i32, i32> = BTree::new(4);
+        let parts = btree.split_into(4);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts.into_iter().flatten().count(), 0);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_par_fold() {
+        let mut btree = BTree::new(8);
+        for i in 0..100000u64 {
+            btree.put(i, i);
+        }
+        let keys = btree.par_fold(
+            Vec::new,
+            |mut v, i| {
+                v.push(i.0);
+                v
+            },
+            |mut a, mut b| {
+                a.append(&mut b);
+                a
+            },
+        );
+        assert_eq!(keys, (0..100000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_write_to_read_from() {
+        let mut rng = StdRng::seed_from_u64(42);
+
+        for m in [4, 32] {
+            let mut btree = BTree::new(m);
+            let mut map = BTreeMap::new();
+            for _ in 0..3000 {
+                let key = rng.gen::<u32>();
+                let value = format!("v{}", rng.gen::<u16>());
+                if rng.gen_bool(0.1) {
+                    btree.put_ttl(key, value.clone(), Duration::from_secs(100));
+                } else {
+                    btree.put(key, value.clone());
+                }
+                map.insert(key, value);
+            }
+
+            let mut buf = Vec::new();
+            btree.write_to(&mut buf).unwrap();
+            let mut copy: BTree<u32, String> = BTree::read_from(buf.as_slice()).unwrap();
+
+            assert_eq!(copy.m, m);
+            assert_eq!(copy.len(), map.len());
+            for (a, b) in btree.iter().zip(copy.iter()) {
+                assert_eq!(a, b);
+            }
+
+            // the loaded tree keeps working
+            for _ in 0..1000 {
+                let key = rng.gen::<u32>();
+                if rng.gen_bool(0.5) {
+                    copy.put(key, String::new());
+                    map.insert(key, String::new());
+                } else {
+                    let k = *map.keys().next().unwrap();
+                    copy.remove(&k);
+                    map.remove(&k);
+                }
+            }
+            let items: Vec<_> = copy.iter().map(|i| (i.0, i.1.clone())).collect();
+            assert_eq!(items, map.into_iter().collect::<Vec<_>>());
+        }
+
+        let empty: BTree<u32, u32> = BTree::new(8);
+        let mut buf = Vec::new();
+        empty.write_to(&mut buf).unwrap();
+        let mut copy: BTree<u32, u32> = BTree::read_from(buf.as_slice()).unwrap();
+        assert!(copy.is_empty());
+        copy.put(1, 1);
+        assert_eq!(copy.len(), 1);
+    }
+
+    #[test]
+    fn test_read_from_bad_data() {
+        let mut btree = BTree::new(8);
+        for i in 0..100u32 {
+            btree.put(i, i);
+        }
+        let mut buf = Vec::new();
+        btree.write_to(&mut buf).unwrap();
+
+        let read = |buf: &[u8]| BTree::<u32, u32>::read_from(buf).unwrap_err().kind();
+
+        assert_eq!(
+            read(&buf[..buf.len() - 1]),
+            std::io::ErrorKind::UnexpectedEof
+        );
+
+        let mut bad = buf.clone();
+        bad[4] = 9;
+        assert_eq!(read(&bad), std::io::ErrorKind::InvalidData);
+
+        // swap the keys 0 and 1, each item is a ttl flag, a key and a value
+        let mut bad = buf.clone();
+        bad[7 + 1] = 1;
+        assert_eq!(read(&bad), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_split_off_all() {
+        let mut btree = BTree::new(4);
+        for i in 0..100 {
+            btree.put(i, i);
+        }
+        let right = btree.split_off(&0);
+        assert_eq!(right.len(), 100);
+        assert!(btree.is_empty());
+        btree.put(1, 1);
+        assert_eq!(btree.get(&1), Some(&1));
+    }
+
     struct A;
     #[test]
     fn test_no_debug_value() {
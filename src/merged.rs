@@ -0,0 +1,335 @@
+use crate::*;
+
+/// The value of a tree in tombstone mode, a delete is kept as a `Delete`
+/// marker so it hides the older versions of the key in a `MergedIter`.
+///
+/// `remove`, and the `delete` and `delete_range` of a batch, take the items
+/// out of the tree and the older versions show again, a tree in tombstone
+/// mode is written with `tombstone` instead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value<V> {
+    Put(V),
+    Delete,
+}
+
+impl<V> Value<V> {
+    /// the value, none for a tombstone
+    pub fn as_put(&self) -> Option<&V> {
+        match self {
+            Value::Put(v) => Some(v),
+            Value::Delete => None,
+        }
+    }
+}
+
+impl<K, V> BTree<K, Value<V>>
+where
+    K: Ord,
+{
+    /// Put a tombstone for a given key instead of removing it, return the old item
+    pub fn tombstone(&mut self, k: K) -> Option<Item<K, Value<V>>> {
+        self.put(k, Value::Delete)
+    }
+}
+
+impl<K, V, C> BatchWrite<K, Value<V>, C>
+where
+    C: Comparator<K>,
+{
+    /// put a tombstone for the key when the batch is applied, unlike `delete`
+    /// it hides the older versions of the key in a `MergedIter`.
+    /// There is no tombstone for a range, a batch does not know the keys
+    /// of the older trees in it
+    pub fn tombstone(&mut self, key: K) {
+        self.put(key, Value::Delete);
+    }
+}
+
+/// A merged view of trees in tombstone mode, the newest first.
+///
+/// For every key only the version of the newest tree holding it counts,
+/// a key whose newest version is a tombstone is hidden. It moves like `Iter`,
+/// after an item `prev` returns the live item before it and `next` the one after it.
+///
+/// # Examples
+/// ```rust
+/// use mem_btree::{BTree, MergedIter, Value};
+///
+/// let mut frozen = BTree::new(32);
+/// frozen.put(1, Value::Put("a"));
+/// frozen.put(2, Value::Put("b"));
+/// let mut active = BTree::new(32);
+/// active.tombstone(1);
+/// active.put(3, Value::Put("c"));
+///
+/// let mut iter = MergedIter::new(vec![active, frozen]);
+/// let keys: Vec<_> = iter.by_ref().map(|item| item.0).collect();
+/// assert_eq!(keys, vec![2, 3]);
+/// iter.seek_prev(&2);
+/// assert_eq!(iter.prev().map(|item| item.0), Some(2));
+/// assert_eq!(iter.prev(), None);
+/// ```
+pub struct MergedIter<K, V>
+where
+    K: Ord,
+{
+    iters: Vec<Iter<K, Value<V>>>,
+    /// the item each iter returned last, the next one in the direction of the move
+    heads: Vec<Option<Item<K, Value<V>>>>,
+    /// true if the heads are the items after the position, none before the first move
+    forward: Option<bool>,
+    /// the item returned last
+    current: Option<Item<K, Value<V>>>,
+}
+
+impl<K, V> MergedIter<K, V>
+where
+    K: Ord,
+{
+    /// Merge the trees, the first one is the newest
+    pub fn new(trees: Vec<BTree<K, Value<V>>>) -> Self {
+        let heads = trees.iter().map(|_| None).collect();
+        Self {
+            iters: trees.into_iter().map(|t| t.iter()).collect(),
+            heads,
+            forward: None,
+            current: None,
+        }
+    }
+
+    /// the smallest head if forward else the largest one, the newest on equal keys
+    fn first_head(&self, forward: bool) -> Option<usize> {
+        let mut first: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let Some(head) = head else { continue };
+            let better = match first {
+                None => true,
+                Some(f) => {
+                    let f = &self.heads[f].as_ref().unwrap().0;
+                    if forward {
+                        head.0 < *f
+                    } else {
+                        head.0 > *f
+                    }
+                }
+            };
+            if better {
+                first = Some(i);
+            }
+        }
+        first
+    }
+
+    /// set the heads to the items on the other side of the position
+    fn turn(&mut self, forward: bool) {
+        let boundary = match &self.current {
+            Some(item) => Some(item.clone()),
+            // after a seek or a tombstone only the heads tell the position,
+            // no item lies between them and it
+            None => self
+                .forward
+                .and_then(|f| self.first_head(f))
+                .and_then(|i| self.heads[i].clone()),
+        };
+
+        for (iter, head) in self.iters.iter_mut().zip(self.heads.iter_mut()) {
+            *head = match &boundary {
+                Some(b) if forward => {
+                    iter.seek(&b.0);
+                    iter.next().filter(|h| h.0 != b.0).or_else(|| iter.next())
+                }
+                Some(b) => {
+                    iter.seek_prev(&b.0);
+                    iter.prev().filter(|h| h.0 != b.0).or_else(|| iter.prev())
+                }
+                None if forward => {
+                    iter.reset();
+                    iter.next()
+                }
+                None => {
+                    iter.reset();
+                    iter.prev()
+                }
+            };
+        }
+        self.forward = Some(forward);
+    }
+
+    fn step(&mut self, forward: bool) -> Option<Item<K, Value<V>>> {
+        if self.forward != Some(forward) {
+            self.turn(forward);
+        }
+
+        loop {
+            let Some(first) = self.first_head(forward) else {
+                self.current = None;
+                return None;
+            };
+            let item = self.heads[first].clone().unwrap();
+
+            // the older versions of the key are skipped
+            for (iter, head) in self.iters.iter_mut().zip(self.heads.iter_mut()) {
+                if matches!(head, Some(h) if h.0 == item.0) {
+                    *head = if forward { iter.next() } else { iter.prev() };
+                }
+            }
+
+            if let Value::Put(_) = item.1 {
+                self.current = Some(item.clone());
+                return Some(item);
+            }
+        }
+    }
+
+    /// the next live item
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<Item<K, Value<V>>> {
+        self.step(true)
+    }
+
+    /// the previous live item
+    pub fn prev(&mut self) -> Option<Item<K, Value<V>>> {
+        self.step(false)
+    }
+
+    /// it is the same as a new MergedIter
+    pub fn reset(&mut self) {
+        self.heads.iter_mut().for_each(|h| *h = None);
+        self.forward = None;
+        self.current = None;
+    }
+
+    /// seek by the key, next returns the first live item not less than key,
+    /// prev the last one less than key
+    pub fn seek(&mut self, key: &K) {
+        for (iter, head) in self.iters.iter_mut().zip(self.heads.iter_mut()) {
+            iter.seek(key);
+            *head = iter.next();
+        }
+        self.forward = Some(true);
+        self.current = None;
+    }
+
+    /// seek prev by the key, prev returns the last live item not greater than key,
+    /// next the first one greater than key
+    pub fn seek_prev(&mut self, key: &K) {
+        for (iter, head) in self.iters.iter_mut().zip(self.heads.iter_mut()) {
+            iter.seek_prev(key);
+            *head = iter.prev();
+        }
+        self.forward = Some(false);
+        self.current = None;
+    }
+}
+
+impl<K: Ord, V> Iterator for MergedIter<K, V> {
+    type Item = Item<K, Value<V>>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{MergedIter, Value};
+    use crate::{BTree, BatchWrite};
+
+    #[test]
+    fn test_merged_iter() {
+        let mut rng = StdRng::seed_from_u64(42);
+
+        for _ in 0..20 {
+            // the oldest first here, the MergedIter takes the newest first
+            let mut trees = Vec::new();
+            let mut map = BTreeMap::new();
+            for _ in 0..rng.gen_range(1..5) {
+                let mut tree = BTree::new(4);
+                for _ in 0..rng.gen_range(0..200) {
+                    let k = rng.gen_range(0..300);
+                    if rng.gen_bool(0.3) {
+                        if rng.gen_bool(0.5) {
+                            tree.tombstone(k);
+                        } else {
+                            let mut bw = BatchWrite::default();
+                            bw.tombstone(k);
+                            tree.write(bw);
+                        }
+                        map.remove(&k);
+                    } else {
+                        let v = rng.gen::<u32>();
+                        tree.put(k, Value::Put(v));
+                        map.insert(k, v);
+                    }
+                }
+                trees.push(tree);
+            }
+            trees.reverse();
+            let all: Vec<(i32, u32)> = map.into_iter().collect();
+            let n = all.len() as isize;
+
+            let item =
+                |i: Option<super::Item<i32, Value<u32>>>| i.map(|i| (i.0, *i.1.as_put().unwrap()));
+
+            let mut iter = MergedIter::new(trees);
+            assert_eq!(
+                iter.by_ref()
+                    .map(|i| item(Some(i)).unwrap())
+                    .collect::<Vec<_>>(),
+                all
+            );
+            iter.reset();
+            let mut reversed = Vec::new();
+            while let Some(i) = iter.prev() {
+                reversed.push(item(Some(i)).unwrap());
+            }
+            reversed.reverse();
+            assert_eq!(reversed, all);
+
+            // the indexes next and prev return
+            let (mut nx, mut pv) = (0isize, n - 1);
+            iter.reset();
+            for _ in 0..500 {
+                match rng.gen_range(0..6) {
+                    0 => {
+                        let k = rng.gen_range(-1..302);
+                        iter.seek(&k);
+                        nx = all.partition_point(|i| i.0 < k) as isize;
+                        pv = nx - 1;
+                    }
+                    1 => {
+                        let k = rng.gen_range(-1..302);
+                        iter.seek_prev(&k);
+                        pv = all.partition_point(|i| i.0 <= k) as isize - 1;
+                        nx = pv + 1;
+                    }
+                    2 | 3 => {
+                        let got = item(iter.next());
+                        if nx < n {
+                            assert_eq!(got, Some(all[nx as usize]));
+                            (nx, pv) = (nx + 1, nx - 1);
+                        } else {
+                            assert_eq!(got, None);
+                            (nx, pv) = (n, n - 1);
+                        }
+                    }
+                    _ => {
+                        let got = item(iter.prev());
+                        if pv >= 0 {
+                            assert_eq!(got, Some(all[pv as usize]));
+                            (nx, pv) = (pv + 1, pv - 1);
+                        } else {
+                            assert_eq!(got, None);
+                            (nx, pv) = (0, -1);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
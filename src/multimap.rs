@@ -0,0 +1,208 @@
+use crate::*;
+
+/// the key of a value in a `MultiMap`, the key and the insertion sequence of the value
+pub type MultiKey<K> = (K, u64);
+
+/// A persistent map from a key to several values in insertion order.
+///
+/// Every value is an item of a `BTree` keyed by the key and a sequence number,
+/// so the values of a key are neighbours in the leaves and are split across
+/// nodes like any other items.
+///
+/// # Examples
+/// ```rust
+/// use mem_btree::MultiMap;
+///
+/// let mut tags = MultiMap::new(32);
+/// tags.insert("a", 1);
+/// tags.insert("b", 2);
+/// tags.insert("a", 3);
+/// tags.insert("a", 1);
+///
+/// let values = |m: &MultiMap<&str, i32>| m.get_all(&"a").map(|i| i.1).collect::<Vec<_>>();
+/// assert_eq!(values(&tags), vec![1, 3, 1]);
+/// tags.remove_one(&"a", &1);
+/// assert_eq!(values(&tags), vec![3, 1]);
+///
+/// let groups: Vec<_> = tags.iter().map(|g| (g[0].0 .0, g.len())).collect();
+/// assert_eq!(groups, vec![("a", 2), ("b", 1)]);
+/// ```
+pub struct MultiMap<K, V> {
+    tree: BTree<MultiKey<K>, V>,
+    /// the sequence number of the next value
+    seq: u64,
+}
+
+/// clone is a snapshot, the same as `BTree`
+impl<K, V> Clone for MultiMap<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+            seq: self.seq,
+        }
+    }
+}
+
+impl<K, V> MultiMap<K, V>
+where
+    K: Ord + Clone,
+{
+    /// Create a new MultiMap with a given branching factor
+    pub fn new(m: usize) -> Self {
+        Self {
+            tree: BTree::new(m),
+            seq: 0,
+        }
+    }
+
+    /// Add a value after the values the key already has
+    pub fn insert(&mut self, k: K, v: V) {
+        self.tree.put((k, self.seq), v);
+        self.seq += 1;
+    }
+
+    /// The values of the key in insertion order
+    pub fn get_all(&self, k: &K) -> MultiValues<K, V> {
+        let mut iter = self.tree.iter();
+        iter.seek(&(k.clone(), 0));
+        MultiValues {
+            iter,
+            key: k.clone(),
+        }
+    }
+
+    /// Remove the oldest value of the key equal to v, return its item
+    pub fn remove_one(&mut self, k: &K, v: &V) -> Option<Item<MultiKey<K>, V>>
+    where
+        V: PartialEq,
+    {
+        let found = self.get_all(k).find(|i| i.1 == *v)?;
+        self.tree.remove(&found.0)
+    }
+
+    /// Remove all the values of the key, return how many there were
+    pub fn remove_all(&mut self, k: &K) -> usize {
+        self.tree
+            .remove_range((k.clone(), 0)..=(k.clone(), u64::MAX))
+    }
+
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.get_all(k).next().is_some()
+    }
+
+    /// The number of values of all keys
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// The values grouped by key, the keys in order
+    pub fn iter(&self) -> MultiGroups<K, V> {
+        MultiGroups {
+            iter: self.tree.iter(),
+            pending: None,
+        }
+    }
+}
+
+/// An iterator over the values of one key of a `MultiMap`
+pub struct MultiValues<K, V>
+where
+    K: Ord,
+{
+    iter: Iter<MultiKey<K>, V>,
+    key: K,
+}
+
+impl<K: Ord, V> Iterator for MultiValues<K, V> {
+    type Item = Item<MultiKey<K>, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().filter(|i| i.0 .0 == self.key)
+    }
+}
+
+/// An iterator over the values of a `MultiMap`, one group of items per key
+pub struct MultiGroups<K, V>
+where
+    K: Ord,
+{
+    iter: Iter<MultiKey<K>, V>,
+    /// the first item of the next group
+    pending: Option<Item<MultiKey<K>, V>>,
+}
+
+impl<K: Ord, V> Iterator for MultiGroups<K, V> {
+    type Item = Vec<Item<MultiKey<K>, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.pending.take().or_else(|| self.iter.next())?;
+        let mut group = vec![first];
+        for item in self.iter.by_ref() {
+            if item.0 .0 != group[0].0 .0 {
+                self.pending = Some(item);
+                break;
+            }
+            group.push(item);
+        }
+        Some(group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::MultiMap;
+
+    #[test]
+    fn test_multimap_same_as_btreemap_of_vec() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut multimap = MultiMap::new(4);
+        let mut map: BTreeMap<i32, Vec<u32>> = BTreeMap::new();
+
+        for round in 0..5000 {
+            let k = rng.gen_range(0..100);
+            match rng.gen_range(0..10) {
+                0..=5 => {
+                    let v = rng.gen_range(0..5);
+                    multimap.insert(k, v);
+                    map.entry(k).or_default().push(v);
+                }
+                6..=8 => {
+                    let v = rng.gen_range(0..5);
+                    let removed = multimap.remove_one(&k, &v).map(|i| i.1);
+                    let values = map.entry(k).or_default();
+                    let expected = values
+                        .iter()
+                        .position(|x| *x == v)
+                        .map(|i| values.remove(i));
+                    assert_eq!(removed, expected);
+                }
+                _ => {
+                    let expected = map.remove(&k).map_or(0, |v| v.len());
+                    assert_eq!(multimap.remove_all(&k), expected);
+                }
+            }
+            map.retain(|_, v| !v.is_empty());
+
+            let values: Vec<u32> = multimap.get_all(&k).map(|i| i.1).collect();
+            assert_eq!(values, map.get(&k).cloned().unwrap_or_default());
+            assert_eq!(multimap.contains_key(&k), map.contains_key(&k));
+
+            if round % 500 == 0 {
+                let groups: Vec<(i32, Vec<u32>)> = multimap
+                    .iter()
+                    .map(|g| (g[0].0 .0, g.iter().map(|i| i.1).collect()))
+                    .collect();
+                assert_eq!(groups, map.clone().into_iter().collect::<Vec<_>>());
+                assert_eq!(multimap.len(), map.values().map(|v| v.len()).sum::<usize>());
+            }
+        }
+    }
+}
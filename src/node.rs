@@ -1,19 +1,36 @@
 use std::sync::Arc;
 
-use crate::*;
+use crate::{
+    batch_write::{Actions, SortedRanges, WriteContext, WriteOutcome},
+    *,
+};
+
+/// a batch smaller than this is not worth a thread
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_ACTIONS: usize = 1024;
+
+/// What a batch does to one child of a node
+enum Task<K, V, A, C> {
+    /// the child is kept as it is
+    Keep(N<K, V, A, C>),
+    /// the child is written with the actions belonging to it
+    Write(N<K, V, A, C>, Actions<K, V>),
+}
 
-pub struct Node<K, V> {
+pub struct Node<K, V, A = (), C = OrdComparator> {
     pub key: Option<Item<K, V>>,
     ttl: Option<Duration>,
     length: usize,
-    pub children: Vec<N<K, V>>,
+    pub aggregate: A,
+    pub children: Vec<N<K, V, A, C>>,
 }
 
-impl<K, V> Node<K, V>
+impl<K, V, A, C> Node<K, V, A, C>
 where
-    K: Ord,
+    A: Aggregate<K, V>,
+    C: Comparator<K>,
 {
-    pub fn instance(children: Vec<N<K, V>>) -> N<K, V> {
+    pub fn instance(children: Vec<N<K, V, A, C>>) -> N<K, V, A, C> {
         let key = if children.is_empty() {
             None
         } else {
@@ -24,6 +41,8 @@ where
 
         let mut length = 0;
 
+        let mut aggregate = A::identity();
+
         for c in children.iter() {
             if let Some(t) = c.ttl() {
                 if let Some(t1) = &ttl {
@@ -36,29 +55,62 @@ where
             }
 
             length += c.len();
+            aggregate = aggregate.combine(c.aggregate());
         }
 
         Arc::new(BTreeType::Node(Self {
             key,
             length,
             ttl,
+            aggregate,
             children,
         }))
     }
 
-    pub fn put(&self, m: usize, k: K, v: V, ttl: Option<Duration>) -> PutResult<K, V> {
+    pub fn put(&self, m: usize, k: K, v: V, ttl: Option<Duration>) -> PutResult<K, V, A, C> {
         let index = self.search_index(&k);
 
         let (values, old) = self.children[index].put(m, k, v, ttl);
 
+        (self.replace_child(m, index, values), old)
+    }
+
+    /// put the operand combined with the value of k by the merge operator,
+    /// in the same descent as the put
+    pub fn merge(
+        &self,
+        m: usize,
+        k: K,
+        operand: V,
+        merge_operator: &dyn MergeOperator<K, V>,
+    ) -> PutResult<K, V, A, C> {
+        let index = self.search_index(&k);
+
+        let (values, old) = self.children[index].merge(m, k, operand, merge_operator);
+
+        (self.replace_child(m, index, values), old)
+    }
+
+    /// the children with the child at index replaced by values
+    fn replace_child(
+        &self,
+        m: usize,
+        index: usize,
+        values: Vec<N<K, V, A, C>>,
+    ) -> Vec<N<K, V, A, C>> {
         let mut children = Vec::with_capacity(self.children.len() + values.len());
 
         children.extend(self.children[..index].iter().cloned());
         children.extend(values);
         children.extend(self.children[index + 1..].iter().cloned());
 
+        Self::split(m, children)
+    }
+
+    /// make one node of the children, or two if there are too many of them
+    pub fn split(m: usize, children: Vec<N<K, V, A, C>>) -> Vec<N<K, V, A, C>> {
         if children.len() < m {
-            return (vec![Self::instance(children)], old);
+            return vec![Self::instance(children)];
         }
 
         let mid = m / 2;
@@ -66,21 +118,21 @@ where
         let left = children[..mid].to_vec();
         let right = children[mid..].to_vec();
 
-        (vec![Self::instance(left), Self::instance(right)], old)
+        vec![Self::instance(left), Self::instance(right)]
     }
 
-    pub fn get(&self, k: &K) -> Option<&V> {
+    pub fn get(&self, k: &K) -> Option<&Item<K, V>> {
         self.children[self.search_index(k)].get(k)
     }
 
-    pub fn remove(&self, k: &K) -> Option<(N<K, V>, Item<K, V>)> {
+    pub fn remove(&self, k: &K) -> RemoveResult<K, V, A, C> {
         let index = self.search_index(k);
 
         let (child, item) = self.children[index].remove(k)?;
 
         let mut children = Vec::with_capacity(self.children.len() - 1);
         children.extend(self.children[..index].iter().cloned());
-        if child.len() > 0 {
+        if !child.is_empty() {
             children.push(child);
         }
         children.extend(self.children[index + 1..].iter().cloned());
@@ -88,7 +140,7 @@ where
         Some((Self::instance(children), item))
     }
 
-    pub fn expir(&self) -> Option<N<K, V>> {
+    pub fn expir(&self) -> Option<N<K, V, A, C>> {
         let now = now();
         match self.ttl {
             Some(t) if t < now => {
@@ -97,7 +149,7 @@ where
                     .iter()
                     .filter_map(|c| {
                         let c = c.expir();
-                        if c.len() > 0 {
+                        if !c.is_empty() {
                             Some(c)
                         } else {
                             None
@@ -110,37 +162,142 @@ where
         }
     }
 
-    pub fn write(&self, m: usize, mut actions: BTreeMap<K, Action<V>>) -> Vec<N<K, V>> {
-        let mut children = Vec::with_capacity(self.children.len() + actions.len());
+    /// split the actions by the children they belong to,
+    /// a child covered by a deleted range is dropped unless `report` is set
+    fn tasks(
+        &self,
+        actions: Actions<K, V>,
+        ranges: &SortedRanges<K, C>,
+        report: bool,
+    ) -> Vec<Task<K, V, A, C>> {
+        let mut tasks = Vec::with_capacity(self.children.len());
+        let mut actions = actions.into_iter().peekable();
+
+        for (index, child) in self.children.iter().enumerate() {
+            if actions.peek().is_none() && ranges.is_empty() {
+                tasks.extend(self.children[index..].iter().cloned().map(Task::Keep));
+                break;
+            }
 
-        let mut start_index = 0;
+            //the actions before next key for current child
+            let part: Actions<K, V> = match self.children.get(index + 1).and_then(|c| c.key()) {
+                Some(k) => std::iter::from_fn(|| actions.next_if(|(a, _)| C::cmp(a, &k.0).is_lt()))
+                    .collect(),
+                None => actions.by_ref().collect(),
+            };
 
-        loop {
-            if let Some((k, _)) = actions.first_key_value() {
-                let index = self.search_index(k);
+            if !part.is_empty() {
+                tasks.push(Task::Write(child.clone(), part));
+                continue;
+            }
 
-                if start_index < index {
-                    children.extend_from_slice(&self.children[start_index..index]);
+            match (child.key(), child.max()) {
+                (Some(min), Some(max)) if ranges.intersects(&min.0, &max.0) => {
+                    if report || !ranges.covers(&min.0, &max.0) {
+                        tasks.push(Task::Write(child.clone(), part));
+                    }
                 }
+                _ => tasks.push(Task::Keep(child.clone())),
+            }
+        }
 
-                if index + 1 < self.children.len() {
-                    //get next key for current childs
-                    if let Some(k) = self.children[index + 1].key() {
-                        let temp = actions.split_off(&k.0);
-                        children.extend(self.children[index].write(m, actions));
-                        start_index = index + 1;
-                        actions = temp;
-                    }
-                } else {
-                    children.extend(self.children[index].write(m, actions));
-                    break;
+        tasks
+    }
+
+    pub fn write(
+        &self,
+        m: usize,
+        actions: Actions<K, V>,
+        ctx: &WriteContext<K, V, C>,
+        mut outcomes: Option<&mut Vec<WriteOutcome<K, V>>>,
+    ) -> Vec<N<K, V, A, C>> {
+        let mut children = Vec::with_capacity(self.children.len() + actions.len());
+
+        // a covered child is dropped whole unless its items must be reported
+        for task in self.tasks(actions, ctx.ranges, outcomes.is_some()) {
+            match task {
+                Task::Keep(child) => children.push(child),
+                Task::Write(child, part) => {
+                    children.extend(child.write(m, part, ctx, outcomes.as_deref_mut()))
                 }
-            } else {
-                children.extend_from_slice(&self.children[start_index..]);
-                break;
             }
         }
 
+        Self::chunks(m, children)
+    }
+
+    /// write like `write`, the children are written by `threads` threads,
+    /// each subtree is a persistent structure of its own
+    #[cfg(feature = "parallel")]
+    pub fn par_write(
+        &self,
+        m: usize,
+        actions: Actions<K, V>,
+        ctx: &WriteContext<K, V, C>,
+        threads: usize,
+    ) -> Vec<N<K, V, A, C>>
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+        A: Send + Sync,
+    {
+        if threads <= 1 || actions.len() < PARALLEL_MIN_ACTIONS {
+            return self.write(m, actions, ctx, None);
+        }
+
+        let tasks = self.tasks(actions, ctx.ranges, false);
+
+        let writes = tasks
+            .iter()
+            .filter(|t| matches!(t, Task::Write(..)))
+            .count()
+            .max(1);
+        let per_thread = writes.div_ceil(threads);
+        let budget = (threads / writes).max(1);
+
+        let mut groups = vec![Vec::new()];
+        let mut count = 0;
+        for task in tasks {
+            if let Task::Write(..) = task {
+                if count == per_thread {
+                    groups.push(Vec::new());
+                    count = 0;
+                }
+                count += 1;
+            }
+            groups.last_mut().unwrap().push(task);
+        }
+
+        let children: Vec<N<K, V, A, C>> = std::thread::scope(|s| {
+            let handles: Vec<_> = groups
+                .into_iter()
+                .map(|group| {
+                    s.spawn(move || {
+                        let mut children = Vec::with_capacity(group.len());
+                        for task in group {
+                            match task {
+                                Task::Keep(child) => children.push(child),
+                                Task::Write(child, part) => {
+                                    children.extend(child.par_write(m, part, ctx, budget))
+                                }
+                            }
+                        }
+                        children
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+                .collect()
+        });
+
+        Self::chunks(m, children)
+    }
+
+    /// group the children into nodes of at most m children
+    pub fn chunks(m: usize, children: Vec<N<K, V, A, C>>) -> Vec<N<K, V, A, C>> {
         children
             .chunks(m)
             .filter_map(|c| {
@@ -157,19 +314,19 @@ where
         self.length
     }
 
-    pub fn split_off(&self, k: &K) -> (N<K, V>, N<K, V>) {
+    pub fn split_off(&self, k: &K, inclusive: bool) -> SplitResult<K, V, A, C> {
         let index = self.search_index(k);
 
-        let (l, r) = self.children[index].split_off(k);
+        let (l, r) = self.children[index].split_off(k, inclusive);
 
         let mut left = Vec::with_capacity(index);
         left.extend_from_slice(&self.children[..index]);
-        if l.len() > 0 {
+        if !l.is_empty() {
             left.push(l);
         }
 
         let mut right = Vec::with_capacity(self.children.len() - index);
-        if r.len() > 0 {
+        if !r.is_empty() {
             right.push(r);
         }
         right.extend_from_slice(&self.children[index + 1..]);
@@ -178,7 +335,10 @@ where
     }
 
     pub fn search_index(&self, k: &K) -> usize {
-        match self.children.binary_search_by(|c| cmp(c.key(), Some(k))) {
+        match self
+            .children
+            .binary_search_by(|c| cmp::<K, V, C>(c.key(), Some(k)))
+        {
             Ok(i) => i,
             Err(i) => {
                 if i == 0 {
@@ -190,10 +350,7 @@ where
         }
     }
 
-    pub(crate) fn ttl(&self) -> Option<&Duration>
-    where
-        K: Ord,
-    {
+    pub(crate) fn ttl(&self) -> Option<&Duration> {
         self.ttl.as_ref()
     }
 }
@@ -0,0 +1,125 @@
+use crate::*;
+
+impl<K, V, A> BTree<K, V, A>
+where
+    K: Ord + AsRef<[u8]>,
+    A: Aggregate<K, V>,
+{
+    /// The items whose keys start with the prefix, in key order.
+    /// The order of the keys must be the order of their bytes, as it is for
+    /// `Vec<u8>` and `String`. The range is found by the cached subtree lengths
+    /// in O(log n), the iterator stops after its last item
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::BTree;
+    /// let mut btree = BTree::new(32);
+    /// for key in ["user:41:name", "user:42:age", "user:42:name", "user:420:name"] {
+    ///     btree.put(key.to_string(), ());
+    /// }
+    /// let keys: Vec<_> = btree.prefix_str("user:42:").map(|item| item.0.clone()).collect();
+    /// assert_eq!(keys, vec!["user:42:age", "user:42:name"]);
+    /// assert_eq!(btree.prefix_count(b"user:42"), 3);
+    /// ```
+    pub fn prefix(&self, prefix: &[u8]) -> Prefix<K, V, A> {
+        let (start, end) = self.prefix_ranks(prefix);
+        let mut iter = self.iter();
+        if start < end {
+            iter.seek_index(start);
+        }
+        Prefix {
+            iter,
+            remaining: end - start,
+        }
+    }
+
+    /// The items whose keys start with the prefix, like `prefix`
+    pub fn prefix_str(&self, prefix: &str) -> Prefix<K, V, A> {
+        self.prefix(prefix.as_bytes())
+    }
+
+    /// The number of keys starting with the prefix in O(log n)
+    pub fn prefix_count(&self, prefix: &[u8]) -> usize {
+        let (start, end) = self.prefix_ranks(prefix);
+        end - start
+    }
+
+    /// the indexes of the first key starting with the prefix and of the first key after them
+    fn prefix_ranks(&self, prefix: &[u8]) -> (usize, usize) {
+        let start = self.root.rank(&|k: &K| k.as_ref() < prefix);
+        let end = self
+            .root
+            .rank(&|k: &K| k.as_ref() < prefix || k.as_ref().starts_with(prefix));
+        (start, end)
+    }
+}
+
+/// An iterator over the items of a `BTree` whose keys start with a prefix
+pub struct Prefix<K, V, A = ()> {
+    iter: Iter<K, V, A>,
+    remaining: usize,
+}
+
+impl<K, V, A> Iterator for Prefix<K, V, A>
+where
+    A: Aggregate<K, V>,
+    K: Ord,
+{
+    type Item = Item<K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        self.iter.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K: Ord, V, A: Aggregate<K, V>> ExactSizeIterator for Prefix<K, V, A> {}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::BTree;
+
+    #[test]
+    fn test_prefix() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut btree = BTree::new(4);
+        let mut keys = std::collections::BTreeSet::new();
+        for _ in 0..3000 {
+            let len = rng.gen_range(0..5);
+            let key: Vec<u8> = (0..len).map(|_| rng.gen_range(0..4)).collect();
+            if rng.gen_bool(0.2) {
+                btree.remove(&key);
+                keys.remove(&key);
+            } else {
+                btree.put(key.clone(), key.len());
+                keys.insert(key);
+            }
+        }
+
+        for len in 0..4 {
+            for _ in 0..30 {
+                let prefix: Vec<u8> = (0..len).map(|_| rng.gen_range(0..4)).collect();
+                let expected: Vec<_> = keys
+                    .iter()
+                    .filter(|k| k.starts_with(&prefix))
+                    .cloned()
+                    .collect();
+                let got: Vec<_> = btree.prefix(&prefix).map(|i| i.0.clone()).collect();
+                assert_eq!(got, expected);
+                assert_eq!(btree.prefix_count(&prefix), expected.len());
+                assert_eq!(btree.prefix(&prefix).len(), expected.len());
+            }
+        }
+        // 255 sorts after every byte used in the keys
+        assert_eq!(btree.prefix(&[3, 255]).next(), None);
+        assert_eq!(btree.prefix_count(&[]), keys.len());
+    }
+}
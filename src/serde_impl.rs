@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+
+use serde::{
+    ser::SerializeMap, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::*;
+
+/// the branching factor of a deserialized tree
+const DEFAULT_M: usize = 32;
+
+/// A tree is serialized as a map in key order, the same as a `BTreeMap`,
+/// the branching factor and the ttl deadlines are left out,
+/// see `serde_ttl` to keep them
+impl<K, V> Serialize for BTree<K, V>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for item in self.iter() {
+            map.serialize_entry(&item.0, &item.1)?;
+        }
+        map.end()
+    }
+}
+
+/// A tree is deserialized from a map with a branching factor of 32,
+/// the last value of a repeated key wins
+impl<'de, K, V> Deserialize<'de> for BTree<K, V>
+where
+    K: Ord + Deserialize<'de>,
+    V: Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let items = BTreeMap::<K, V>::deserialize(deserializer)?;
+        Ok(BTree::bulk_load(
+            DEFAULT_M,
+            items.into_iter().map(|(k, v)| (k, v, None)),
+        ))
+    }
+}
+
+/// A batch is serialized as its deleted ranges and its point actions,
+/// it is applied the same after a round trip, except a compare and set
+/// with a custom `eq`, which compares by `PartialEq` after it
+impl<K, V> Serialize for BatchWrite<K, V>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ranges: Vec<_> = self.ranges().collect();
+        let actions: Vec<_> = self.actions().collect();
+
+        let mut s = serializer.serialize_struct("BatchWrite", 2)?;
+        s.serialize_field("ranges", &ranges)?;
+        s.serialize_field("actions", &actions)?;
+        s.end()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(
+    rename = "BatchWrite",
+    bound(deserialize = "K: Deserialize<'de>, V: Deserialize<'de> + PartialEq")
+)]
+struct BatchWriteData<K, V> {
+    ranges: Vec<(K, K)>,
+    actions: Vec<(K, Action<V>)>,
+}
+
+impl<'de, K, V> Deserialize<'de> for BatchWrite<K, V>
+where
+    K: Ord + Deserialize<'de>,
+    V: Deserialize<'de> + PartialEq,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = BatchWriteData::deserialize(deserializer)?;
+
+        // the ranges first, they override the point actions added before them
+        let mut batch_write = BatchWrite::default();
+        for (start, end) in data.ranges {
+            batch_write.delete_range(start..end);
+        }
+        for (k, action) in data.actions {
+            batch_write.push(k, action);
+        }
+        Ok(batch_write)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::{Action, BTree, BatchWrite};
+
+    #[test]
+    fn test_serde_btree() {
+        let mut btree = BTree::new(4);
+        for i in (0..100).rev() {
+            btree.put(i, format!("v{i}"));
+        }
+        btree.put_ttl(100, String::from("ttl"), Duration::from_secs(100));
+
+        let json = serde_json::to_string(&btree).unwrap();
+        assert!(json.starts_with(r#"{"0":"v0","1":"v1","#));
+
+        // interchangeable with a BTreeMap
+        let map: BTreeMap<i32, String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&map).unwrap(), json);
+
+        let copy: BTree<i32, String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(copy.m, 32);
+        assert_eq!(copy.len(), 101);
+        for (a, b) in btree.iter().zip(copy.iter()) {
+            assert_eq!((&a.0, &a.1), (&b.0, &b.1));
+        }
+        assert_eq!(copy.iter().last().unwrap().2, None);
+        let copy: BTree<i32, String> =
+            serde_json::from_str(r#"{"2":"a","1":"b","2":"c"}"#).unwrap();
+        assert_eq!(
+            serde_json::to_string(&copy).unwrap(),
+            r#"{"1":"b","2":"c"}"#
+        );
+
+        #[derive(Serialize, Deserialize)]
+        struct Wrap {
+            #[serde(with = "crate::serde_ttl")]
+            tree: BTree<i32, String>,
+        }
+
+        let json = serde_json::to_string(&Wrap {
+            tree: btree.clone(),
+        })
+        .unwrap();
+        let copy = serde_json::from_str::<Wrap>(&json).unwrap().tree;
+        assert_eq!(copy.m, 4);
+        for (a, b) in btree.iter().zip(copy.iter()) {
+            assert_eq!(a, b);
+        }
+
+        let bad = r#"{"tree":{"m":4,"items":[[2,"a",null],[1,"b",null]]}}"#;
+        assert!(serde_json::from_str::<Wrap>(bad).is_err());
+    }
+
+    #[test]
+    fn test_serde_batch_write() {
+        let mut bw = BatchWrite::default();
+        bw.put(1, 10);
+        bw.delete_range(0..5);
+        bw.put(2, 20);
+        bw.compare_and_set(3, 30, 31);
+        bw.put_if_absent(3, 32);
+        bw.merge(9, 1);
+        bw.delete(8);
+
+        let json = serde_json::to_string(&bw).unwrap();
+        let copy: BatchWrite<i32, i32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&copy).unwrap(), json);
+
+        let operator = |_k: &i32, v: Option<&i32>, op: i32| v.unwrap_or(&0) + op;
+        let mut base = BTree::new(4).with_merge_operator(operator);
+        for i in 0..10 {
+            base.put(i, i * 10);
+        }
+        let mut a = base.clone();
+        a.write(bw);
+        let mut b = base.clone();
+        b.write(copy);
+        let collect = |t: &BTree<i32, i32>| t.iter().map(|i| (i.0, i.1)).collect::<Vec<_>>();
+        assert_eq!(collect(&a), collect(&b));
+        assert_eq!(
+            collect(&a)[..5],
+            [(2, 20), (3, 32), (5, 50), (6, 60), (7, 70)]
+        );
+
+        let action: Action<i32> =
+            serde_json::from_str(r#"{"CompareAndSet":{"expected":1,"value":2,"ttl":null}}"#)
+                .unwrap();
+        match action {
+            Action::CompareAndSet { expected, eq, .. } => assert!(eq(&expected, &1)),
+            _ => panic!("{action:?}"),
+        }
+    }
+}
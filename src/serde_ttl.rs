@@ -0,0 +1,83 @@
+//! Serialize a BTree with its ttl deadlines and branching factor,
+//! for `#[serde(with = "mem_btree::serde_ttl")]`, the plain `Serialize` of a
+//! tree is the map of a `BTreeMap` and deserializes with a branching factor of 32
+//!
+//! The tree is a struct of `m` and the items as `(key, value, deadline)` in key order.
+//!
+//! # Examples
+//! ```rust
+//! use std::time::Duration;
+//! use mem_btree::BTree;
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Cache {
+//!     #[serde(with = "mem_btree::serde_ttl")]
+//!     tree: BTree<String, u64>,
+//! }
+//!
+//! let mut tree = BTree::new(32);
+//! tree.put_ttl(String::from("a"), 1, Duration::from_secs(60));
+//! let json = serde_json::to_string(&Cache { tree }).unwrap();
+//! let cache: Cache = serde_json::from_str(&json).unwrap();
+//! assert!(cache.tree.iter().next().unwrap().2.is_some());
+//! ```
+
+use serde::{
+    de::Error, ser::SerializeSeq, ser::SerializeStruct, Deserialize, Deserializer, Serialize,
+    Serializer,
+};
+
+use crate::*;
+
+struct Items<'a, K, V>(&'a BTree<K, V>);
+
+impl<K, V> Serialize for Items<'_, K, V>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for item in self.0.iter() {
+            seq.serialize_element(&(&item.0, &item.1, &item.2))?;
+        }
+        seq.end()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "BTree")]
+struct TtlTree<K, V> {
+    m: usize,
+    items: Vec<(K, V, Option<Duration>)>,
+}
+
+pub fn serialize<K, V, S>(tree: &BTree<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+    S: Serializer,
+{
+    let mut s = serializer.serialize_struct("BTree", 2)?;
+    s.serialize_field("m", &tree.m)?;
+    s.serialize_field("items", &Items(tree))?;
+    s.end()
+}
+
+/// the items must be in strictly increasing key order, as `serialize` writes them
+pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<BTree<K, V>, D::Error>
+where
+    K: Ord + Deserialize<'de>,
+    V: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    let data = TtlTree::<K, V>::deserialize(deserializer)?;
+    if data.m < 2 {
+        return Err(D::Error::custom(format!("bad m {}", data.m)));
+    }
+    if data.items.windows(2).any(|w| w[0].0 >= w[1].0) {
+        return Err(D::Error::custom("keys are not strictly increasing"));
+    }
+    Ok(BTree::bulk_load(data.m, data.items))
+}
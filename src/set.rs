@@ -0,0 +1,468 @@
+use std::{
+    cmp::Ordering,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{algebra::SetOp, *};
+
+/// the number of keys in the blocks of a subtree
+#[derive(Clone)]
+struct Count(usize);
+
+impl<K> Aggregate<K, Arc<[K]>> for Count {
+    fn identity() -> Self {
+        Count(0)
+    }
+
+    fn from_item(_: &K, keys: &Arc<[K]>) -> Self {
+        Count(keys.len())
+    }
+
+    fn combine(&self, other: &Self) -> Self {
+        Count(self.0 + other.0)
+    }
+}
+
+/// the sorted keys in blocks of up to m, keyed by their first key
+type Blocks<K> = BTree<K, Arc<[K]>, Count>;
+
+/// a node of the blocks tree
+type BlockNode<K> = BTreeType<K, Arc<[K]>, Count>;
+
+/// A persistent ordered set storing only its keys.
+///
+/// The keys are kept in sorted blocks of up to m keys, the blocks are the
+/// items of a `BTree` keyed by their first key, like the blocks of
+/// `CompactBTree`. A key takes its own size in its block, not an `Arc` of
+/// the key, `()` and the ttl slot as an item of `BTree<K, ()>` does, only the
+/// first key of every block is stored twice. An insert or remove copies the
+/// keys of its block and an overfull block splits in halves. Clone is a
+/// snapshot like `BTree`, the set algebra merges the keys of both sets in
+/// linear time.
+///
+/// # Examples
+/// ```rust
+/// use mem_btree::BTreeSet;
+///
+/// let mut a = BTreeSet::new(32);
+/// let mut b = BTreeSet::new(32);
+/// for i in 0..10 {
+///     a.insert(i);
+///     b.insert(i + 5);
+/// }
+/// let snapshot = a.clone();
+/// a.remove(&0);
+/// assert!(snapshot.contains(&0));
+///
+/// let keys = |s: &BTreeSet<i32>| s.iter().copied().collect::<Vec<_>>();
+/// assert_eq!(keys(&a.intersection(&b)), vec![5, 6, 7, 8, 9]);
+/// assert_eq!(keys(&a.difference(&b)), vec![1, 2, 3, 4]);
+/// assert_eq!(a.range(3..6).copied().collect::<Vec<_>>(), vec![3, 4, 5]);
+/// ```
+pub struct BTreeSet<K> {
+    m: usize,
+    blocks: Blocks<K>,
+}
+
+/// clone is a snapshot, it only clones the root `Arc`
+impl<K> Clone for BTreeSet<K> {
+    fn clone(&self) -> Self {
+        Self {
+            m: self.m,
+            blocks: self.blocks.clone(),
+        }
+    }
+}
+
+impl<K> BTreeSet<K>
+where
+    K: Ord + Clone,
+{
+    /// Create a new BTreeSet with a given branching factor,
+    /// it is also the number of keys in a block
+    pub fn new(m: usize) -> Self {
+        Self {
+            m,
+            blocks: BTree::with_aggregate(m),
+        }
+    }
+
+    /// the block that would hold k, the last one starting at or before it,
+    /// the first block if there is none
+    fn block(&self, k: &K) -> Option<&Item<K, Arc<[K]>>> {
+        let index = self.blocks.root.rank(&|first| first <= k);
+        self.blocks.get_index(index.saturating_sub(1))
+    }
+
+    /// Insert the key, return false if it was in the set
+    pub fn insert(&mut self, k: K) -> bool {
+        let Some(block) = self.block(&k).cloned() else {
+            self.blocks.put(k.clone(), Arc::from(vec![k]));
+            return true;
+        };
+        let Err(i) = block.1.binary_search(&k) else {
+            return false;
+        };
+        let mut keys = block.1.to_vec();
+        keys.insert(i, k);
+        self.replace(&block.0, keys);
+        true
+    }
+
+    /// Remove the key, return false if it was not in the set
+    pub fn remove(&mut self, k: &K) -> bool {
+        let Some(block) = self.block(k).cloned() else {
+            return false;
+        };
+        let Ok(i) = block.1.binary_search(k) else {
+            return false;
+        };
+        let mut keys = block.1.to_vec();
+        keys.remove(i);
+        self.replace(&block.0, keys);
+        true
+    }
+
+    /// replace the block starting at first by the keys,
+    /// an overfull block is split in halves
+    fn replace(&mut self, first: &K, mut keys: Vec<K>) {
+        if keys.first() != Some(first) {
+            self.blocks.remove(first);
+        }
+        if keys.len() > self.m {
+            let right = keys.split_off(keys.len() / 2);
+            self.blocks.put(right[0].clone(), Arc::from(right));
+        }
+        if let Some(first) = keys.first() {
+            self.blocks.put(first.clone(), Arc::from(keys));
+        }
+    }
+
+    pub fn contains(&self, k: &K) -> bool {
+        self.block(k)
+            .is_some_and(|block| block.1.binary_search(k).is_ok())
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.root.aggregate().0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the number of keys less than k, or not greater than k if inclusive
+    fn rank(&self, k: &K, inclusive: bool) -> usize {
+        let Some(block) = self.block(k) else {
+            return 0;
+        };
+        let before = self
+            .blocks
+            .aggregate((Bound::Unbounded, Bound::Excluded(&block.0)));
+        before.0 + block.1.partition_point(|x| x < k || (inclusive && x == k))
+    }
+
+    /// All the keys in order
+    pub fn iter(&self) -> SetIter<'_, K> {
+        SetIter::new(&self.blocks.root, 0, self.len())
+    }
+
+    /// The keys in the range in order
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> SetIter<'_, K> {
+        let start = match range.start_bound() {
+            Bound::Included(s) => self.rank(s, false),
+            Bound::Excluded(s) => self.rank(s, true),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(e) => self.rank(e, true),
+            Bound::Excluded(e) => self.rank(e, false),
+            Bound::Unbounded => self.len(),
+        };
+        SetIter::new(&self.blocks.root, start, end.saturating_sub(start))
+    }
+
+    /// Split off the keys not less than k into a new set
+    pub fn split_off(&mut self, k: &K) -> Self {
+        let mut right = self.blocks.split_off(k);
+
+        // the last block on the left may hold keys from k on
+        let last = self
+            .blocks
+            .len()
+            .checked_sub(1)
+            .and_then(|i| self.blocks.get_index(i))
+            .cloned();
+        if let Some(last) = last {
+            let at = last.1.partition_point(|x| x < k);
+            if at < last.1.len() {
+                // its first key is less than k, the left part is not empty
+                self.blocks.put(last.0.clone(), Arc::from(&last.1[..at]));
+                right.put(last.1[at].clone(), Arc::from(&last.1[at..]));
+            }
+        }
+
+        Self {
+            m: self.m,
+            blocks: right,
+        }
+    }
+
+    /// The keys in self or in other
+    pub fn union(&self, other: &Self) -> Self {
+        self.combine(other, SetOp::Union)
+    }
+
+    /// The keys in both self and other
+    pub fn intersection(&self, other: &Self) -> Self {
+        self.combine(other, SetOp::Intersection)
+    }
+
+    /// The keys in self but not in other
+    pub fn difference(&self, other: &Self) -> Self {
+        self.combine(other, SetOp::Difference)
+    }
+
+    /// The keys in exactly one of self and other
+    pub fn symmetric_difference(&self, other: &Self) -> Self {
+        self.combine(other, SetOp::SymmetricDifference)
+    }
+
+    /// merge the keys of both sets and fill the blocks of the result
+    fn combine(&self, other: &Self, op: SetOp) -> Self {
+        let (mut a, mut b) = (self.iter().peekable(), other.iter().peekable());
+        let mut keys = Vec::new();
+        loop {
+            let (k, in_a, in_b) = match (a.peek(), b.peek()) {
+                (None, None) => break,
+                (Some(_), None) => (a.next(), true, false),
+                (None, Some(_)) => (b.next(), false, true),
+                (Some(x), Some(y)) => match x.cmp(y) {
+                    Ordering::Less => (a.next(), true, false),
+                    Ordering::Greater => (b.next(), false, true),
+                    Ordering::Equal => {
+                        b.next();
+                        (a.next(), true, true)
+                    }
+                },
+            };
+            if op.keep(in_a, in_b) {
+                keys.extend(k.cloned());
+            }
+        }
+
+        let items = keys
+            .chunks(self.m)
+            .map(|c| Arc::new((c[0].clone(), Arc::from(c), None)))
+            .collect();
+        Self {
+            m: self.m,
+            blocks: BTree::from_sorted_items(self.m, items),
+        }
+    }
+}
+
+impl<K: Debug + Ord + Clone> Debug for BTreeSet<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// An iterator over the keys of a `BTreeSet` in order, it borrows the blocks of the set
+pub struct SetIter<'a, K> {
+    /// the nodes from the root to the leaf of the current block,
+    /// with the index of the next child or block in each
+    stack: Vec<(&'a BlockNode<K>, usize)>,
+    /// the keys left in the current block
+    keys: std::slice::Iter<'a, K>,
+    /// the keys left to return
+    remaining: usize,
+}
+
+impl<'a, K: Ord> SetIter<'a, K> {
+    /// the iterator over `remaining` keys from the key at index in key order
+    fn new(root: &'a BlockNode<K>, mut index: usize, remaining: usize) -> Self {
+        let mut stack = Vec::new();
+        let mut node = root;
+        loop {
+            match node {
+                BTreeType::Leaf(l) => {
+                    let mut i = 0;
+                    while i < l.items.len() && index >= l.items[i].1.len() {
+                        index -= l.items[i].1.len();
+                        i += 1;
+                    }
+                    let keys = match l.items.get(i) {
+                        Some(block) => block.1[index..].iter(),
+                        None => [].iter(),
+                    };
+                    stack.push((node, i + 1));
+                    return Self {
+                        stack,
+                        keys,
+                        remaining,
+                    };
+                }
+                BTreeType::Node(n) => {
+                    let mut i = 0;
+                    while i + 1 < n.children.len() && index >= n.children[i].aggregate().0 {
+                        index -= n.children[i].aggregate().0;
+                        i += 1;
+                    }
+                    stack.push((node, i + 1));
+                    node = &n.children[i];
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K> Iterator for SetIter<'a, K> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        loop {
+            if let Some(k) = self.keys.next() {
+                self.remaining -= 1;
+                return Some(k);
+            }
+            let (node, index) = self.stack.last_mut()?;
+            match *node {
+                BTreeType::Leaf(l) if *index < l.items.len() => {
+                    self.keys = l.items[*index].1.iter();
+                    *index += 1;
+                }
+                BTreeType::Node(n) if *index < n.children.len() => {
+                    let child: &'a BlockNode<K> = &n.children[*index];
+                    *index += 1;
+                    self.stack.push((child, 0));
+                }
+                _ => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K> ExactSizeIterator for SetIter<'_, K> {}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::BTreeSet;
+
+    fn keys(set: &BTreeSet<i32>) -> Vec<i32> {
+        set.iter().copied().collect()
+    }
+
+    fn bound(rng: &mut StdRng) -> Bound<i32> {
+        match rng.gen_range(0..3) {
+            0 => Bound::Included(rng.gen_range(-10..510)),
+            1 => Bound::Excluded(rng.gen_range(-10..510)),
+            _ => Bound::Unbounded,
+        }
+    }
+
+    fn random_set(rng: &mut StdRng) -> (BTreeSet<i32>, std::collections::BTreeSet<i32>) {
+        let mut set = BTreeSet::new(4);
+        let mut expected = std::collections::BTreeSet::new();
+        for _ in 0..rng.gen_range(0..300) {
+            let k = rng.gen_range(0..500);
+            if rng.gen_bool(0.3) {
+                assert_eq!(set.remove(&k), expected.remove(&k));
+            } else {
+                assert_eq!(set.insert(k), expected.insert(k));
+            }
+        }
+        (set, expected)
+    }
+
+    #[test]
+    fn test_set_same_as_btreeset() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..50 {
+            let (mut set, mut expected) = random_set(&mut rng);
+            assert_eq!(set.len(), expected.len());
+            assert_eq!(keys(&set), expected.iter().cloned().collect::<Vec<_>>());
+            for k in -1..501 {
+                assert_eq!(set.contains(&k), expected.contains(&k));
+            }
+
+            for _ in 0..20 {
+                let (start, end) = (bound(&mut rng), bound(&mut rng));
+                let range = set.range((start, end));
+                let len = range.len();
+                let got: Vec<_> = range.copied().collect();
+                assert_eq!(got.len(), len);
+                let want: Vec<_> = expected
+                    .iter()
+                    .filter(|k| std::ops::RangeBounds::contains(&(start, end), *k))
+                    .cloned()
+                    .collect();
+                assert_eq!(got, want);
+            }
+
+            let k = rng.gen_range(-10..510);
+            let snapshot = set.clone();
+            let right = set.split_off(&k);
+            let expected_right = expected.split_off(&k);
+            assert_eq!(keys(&set), expected.iter().cloned().collect::<Vec<_>>());
+            assert_eq!(
+                keys(&right),
+                expected_right.iter().cloned().collect::<Vec<_>>()
+            );
+            assert_eq!(snapshot.len(), set.len() + right.len());
+        }
+    }
+
+    #[test]
+    fn test_set_algebra() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..50 {
+            let (a, ea) = random_set(&mut rng);
+            let (b, eb) = random_set(&mut rng);
+
+            assert_eq!(
+                keys(&a.union(&b)),
+                ea.union(&eb).cloned().collect::<Vec<_>>()
+            );
+            assert_eq!(
+                keys(&a.intersection(&b)),
+                ea.intersection(&eb).cloned().collect::<Vec<_>>()
+            );
+            assert_eq!(
+                keys(&a.difference(&b)),
+                ea.difference(&eb).cloned().collect::<Vec<_>>()
+            );
+            assert_eq!(
+                keys(&a.symmetric_difference(&b)),
+                ea.symmetric_difference(&eb).cloned().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_set_blocks() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut set = BTreeSet::new(16);
+        for _ in 0..2000 {
+            set.insert(rng.gen_range(0..100_000));
+        }
+        // the keys are held in blocks, not an item per key
+        let sizes: Vec<_> = set.blocks.iter().map(|b| b.1.len()).collect();
+        assert_eq!(sizes.iter().sum::<usize>(), set.len());
+        assert!(sizes.iter().all(|&n| (8..=16).contains(&n)), "{sizes:?}");
+        assert!(set.blocks.iter().all(|b| b.0 == b.1[0]));
+    }
+}
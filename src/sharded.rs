@@ -0,0 +1,408 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    hash::{DefaultHasher, Hash, Hasher},
+    ops::Range,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use crate::*;
+
+/// How a key finds its shard
+enum Routing<K> {
+    /// shard i holds the keys from boundary i - 1 to boundary i
+    Range(Vec<K>),
+    /// the hash of the key modulo the number of shards
+    Hash(usize, fn(&K) -> u64),
+}
+
+impl<K> Routing<K>
+where
+    K: Ord,
+{
+    fn shard(&self, k: &K) -> usize {
+        match self {
+            Routing::Range(boundaries) => boundaries.partition_point(|b| b <= k),
+            Routing::Hash(n, hash) => (hash(k) % *n as u64) as usize,
+        }
+    }
+
+    /// the shards holding some keys of the range
+    fn shards(&self, range: &Range<K>) -> Range<usize> {
+        match self {
+            Routing::Range(boundaries) => {
+                self.shard(&range.start)..boundaries.partition_point(|b| *b < range.end) + 1
+            }
+            Routing::Hash(n, _) => 0..*n,
+        }
+    }
+}
+
+fn hash_key<K: Hash>(k: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    k.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A tree partitioned into several BTrees by key, so writers of different
+/// shards do not wait for each other.
+///
+/// Every shard has its own writer lock. The published roots of all shards are
+/// swapped together, so a snapshot sees every batch on all of its shards or
+/// on none of them.
+///
+/// # Examples
+/// ```rust
+/// use mem_btree::ShardedBTree;
+///
+/// let sharded = ShardedBTree::with_ranges(32, vec![100, 200]);
+/// for i in (0..300).rev() {
+///     sharded.put(i, i);
+/// }
+/// let snapshot = sharded.snapshot();
+/// assert_eq!(snapshot.shards()[1].len(), 100);
+/// let keys: Vec<_> = snapshot.iter().map(|item| item.0).collect();
+/// assert_eq!(keys, (0..300).collect::<Vec<_>>());
+/// ```
+pub struct ShardedBTree<K, V> {
+    routing: Arc<Routing<K>>,
+    /// serializes the writers of each shard
+    writers: Vec<Mutex<()>>,
+    /// the last published tree of every shard
+    current: RwLock<Vec<BTree<K, V>>>,
+}
+
+impl<K, V> ShardedBTree<K, V>
+where
+    K: Ord,
+{
+    /// Create a tree split by the sorted boundaries into `boundaries.len() + 1` shards,
+    /// keys in order stay together, it suits range scans
+    pub fn with_ranges(m: usize, mut boundaries: Vec<K>) -> Self {
+        boundaries.sort();
+        boundaries.dedup();
+        let n = boundaries.len() + 1;
+        Self::new(m, n, Routing::Range(boundaries))
+    }
+
+    /// Create a tree of n shards chosen by the hash of the key,
+    /// it spreads point writes evenly whatever the keys are
+    pub fn with_hash(m: usize, n: usize) -> Self
+    where
+        K: Hash,
+    {
+        let n = n.max(1);
+        Self::new(m, n, Routing::Hash(n, hash_key::<K>))
+    }
+
+    fn new(m: usize, n: usize, routing: Routing<K>) -> Self {
+        Self {
+            routing: Arc::new(routing),
+            writers: (0..n).map(|_| Mutex::new(())).collect(),
+            current: RwLock::new((0..n).map(|_| BTree::new(m)).collect()),
+        }
+    }
+
+    /// Get a snapshot of the last published trees of all shards
+    pub fn snapshot(&self) -> ShardedSnapshot<K, V> {
+        let shards = self
+            .current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        ShardedSnapshot {
+            routing: self.routing.clone(),
+            shards,
+        }
+    }
+
+    /// Get the item for a given key in the last published tree of its shard
+    pub fn get(&self, k: &K) -> Option<Item<K, V>> {
+        let current = self.current.read().unwrap_or_else(|e| e.into_inner());
+        current[self.routing.shard(k)].get_item(k).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshot().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshot().is_empty()
+    }
+
+    /// Change the trees of the shards by f and publish them together.
+    /// The shards must be sorted, their writers are locked in that order
+    fn update<F, R>(&self, shards: &[usize], f: F) -> R
+    where
+        F: FnOnce(&mut [BTree<K, V>]) -> R,
+    {
+        let _writers: Vec<_> = shards
+            .iter()
+            .map(|i| self.writers[*i].lock().unwrap_or_else(|e| e.into_inner()))
+            .collect();
+
+        let mut trees: Vec<_> = {
+            let current = self.current.read().unwrap_or_else(|e| e.into_inner());
+            shards.iter().map(|i| current[*i].clone()).collect()
+        };
+
+        let result = f(&mut trees);
+
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        for (i, tree) in shards.iter().zip(trees) {
+            current[*i] = tree;
+        }
+        result
+    }
+
+    /// Insert a key-value pair and publish it, return the old item
+    pub fn put(&self, k: K, v: V) -> Option<Item<K, V>> {
+        self.update(&[self.routing.shard(&k)], |trees| trees[0].put(k, v))
+    }
+
+    /// Insert a key-value pair with ttl and publish it, return the old item
+    pub fn put_ttl(&self, k: K, v: V, ttl: Duration) -> Option<Item<K, V>> {
+        self.update(&[self.routing.shard(&k)], |trees| {
+            trees[0].put_ttl(k, v, ttl)
+        })
+    }
+
+    /// Remove a key and publish it, return the old item
+    pub fn remove(&self, k: &K) -> Option<Item<K, V>> {
+        self.update(&[self.routing.shard(k)], |trees| trees[0].remove(k))
+    }
+
+    /// Write a batch and publish all of it at once,
+    /// only the writers of the shards it touches are waited for
+    pub fn write(&self, batch_write: BatchWrite<K, V>)
+    where
+        K: Clone,
+    {
+        let (actions, ranges) = batch_write.into_parts();
+
+        let mut batches: Vec<BatchWrite<K, V>> =
+            self.writers.iter().map(|_| BatchWrite::default()).collect();
+
+        // the ranges of a batch are applied before its point actions
+        for (start, end) in ranges.into_inner() {
+            let range = start..end;
+            for i in self.routing.shards(&range) {
+                batches[i].delete_range(range.clone());
+            }
+        }
+        for (k, action) in actions {
+            batches[self.routing.shard(&k)].push(k, action);
+        }
+
+        let (shards, batches): (Vec<_>, Vec<_>) = batches
+            .into_iter()
+            .enumerate()
+            .filter(|(_, bw)| !bw.is_empty())
+            .unzip();
+
+        self.update(&shards, |trees| {
+            for (tree, bw) in trees.iter_mut().zip(batches) {
+                tree.write(bw);
+            }
+        })
+    }
+}
+
+/// The trees of all shards of a ShardedBTree at one moment
+pub struct ShardedSnapshot<K, V> {
+    routing: Arc<Routing<K>>,
+    shards: Vec<BTree<K, V>>,
+}
+
+impl<K, V> ShardedSnapshot<K, V>
+where
+    K: Ord,
+{
+    /// Get the value for a given key
+    pub fn get(&self, k: &K) -> Option<&V> {
+        self.shards[self.routing.shard(k)].get(k)
+    }
+
+    /// The tree of every shard
+    pub fn shards(&self) -> &[BTree<K, V>] {
+        &self.shards
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.is_empty())
+    }
+
+    /// Iterate the items of all shards in key order
+    pub fn iter(&self) -> ShardedIter<K, V> {
+        let mut iters: Vec<_> = self.shards.iter().map(|s| s.iter()).collect();
+        let heap = iters
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(shard, iter)| iter.next().map(|item| Head { item, shard }))
+            .collect();
+        ShardedIter { iters, heap }
+    }
+}
+
+/// The next item of one shard
+struct Head<K, V> {
+    item: Item<K, V>,
+    shard: usize,
+}
+
+impl<K: Ord, V> Ord for Head<K, V> {
+    /// reversed, the BinaryHeap pops the smallest key first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .item
+            .0
+            .cmp(&self.item.0)
+            .then(other.shard.cmp(&self.shard))
+    }
+}
+
+impl<K: Ord, V> PartialOrd for Head<K, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord, V> PartialEq for Head<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: Ord, V> Eq for Head<K, V> {}
+
+/// A k-way merge of the iterators of all shards
+pub struct ShardedIter<K, V>
+where
+    K: Ord,
+{
+    iters: Vec<Iter<K, V>>,
+    heap: BinaryHeap<Head<K, V>>,
+}
+
+impl<K: Ord, V> Iterator for ShardedIter<K, V> {
+    type Item = Item<K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Head { item, shard } = self.heap.pop()?;
+        if let Some(next) = self.iters[shard].next() {
+            self.heap.push(Head { item: next, shard });
+        }
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::ShardedBTree;
+    use crate::BatchWrite;
+
+    #[test]
+    fn test_sharded_same_as_btreemap() {
+        let mut rng = StdRng::seed_from_u64(42);
+
+        for sharded in [
+            ShardedBTree::with_ranges(4, vec![250, 500, 750]),
+            ShardedBTree::with_hash(4, 5),
+        ] {
+            let mut map = BTreeMap::new();
+            for _ in 0..300 {
+                let mut bw = BatchWrite::default();
+                let start = rng.gen_range(0..1000);
+                let end = start + rng.gen_range(0..100);
+                bw.delete_range(start..end);
+                map.retain(|k, _| !(start..end).contains(k));
+                for _ in 0..rng.gen_range(0..20) {
+                    let k = rng.gen_range(0..1000);
+                    if rng.gen_bool(0.2) {
+                        bw.delete(k);
+                        map.remove(&k);
+                    } else {
+                        bw.put(k, k + 1);
+                        map.insert(k, k + 1);
+                    }
+                }
+                sharded.write(bw);
+
+                let k = rng.gen_range(0..1000);
+                assert_eq!(sharded.put(k, k).map(|i| i.1), map.insert(k, k));
+                let k = rng.gen_range(0..1000);
+                assert_eq!(sharded.remove(&k).map(|i| i.1), map.remove(&k));
+            }
+
+            let snapshot = sharded.snapshot();
+            assert_eq!(snapshot.len(), map.len());
+            let items: Vec<_> = snapshot.iter().map(|i| (i.0, i.1)).collect();
+            assert_eq!(items, map.into_iter().collect::<Vec<_>>());
+            for k in 0..1000 {
+                assert_eq!(snapshot.get(&k).copied(), sharded.get(&k).map(|i| i.1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_snapshot_across_shards() {
+        let sharded = Arc::new(ShardedBTree::with_hash(8, 4));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let sharded = sharded.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        // every batch writes the same version to keys of all shards
+                        let snapshot = sharded.snapshot();
+                        let versions: Vec<_> = snapshot.iter().map(|i| i.1).collect();
+                        assert!(versions.windows(2).all(|w| w[0] == w[1]));
+                    }
+                })
+            })
+            .collect();
+
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let sharded = sharded.clone();
+                std::thread::spawn(move || {
+                    for version in 0..200 {
+                        let mut bw = BatchWrite::default();
+                        for k in 0..100 {
+                            bw.put(k, version);
+                        }
+                        sharded.write(bw);
+                    }
+                })
+            })
+            .collect();
+
+        for w in writers {
+            w.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        for r in readers {
+            r.join().unwrap();
+        }
+
+        let snapshot = sharded.snapshot();
+        assert_eq!(snapshot.len(), 100);
+        assert!(snapshot.shards().iter().all(|s| !s.is_empty()));
+    }
+}
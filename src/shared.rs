@@ -0,0 +1,209 @@
+use std::sync::{Mutex, RwLock};
+
+use crate::*;
+
+/// A BTree shared by threads.
+///
+/// Readers take a snapshot of the last published root, writers are serialized
+/// and work on their own snapshot, then publish the new root by swapping it in.
+/// A reader never waits for the work of a writer, only for the swap of one `Arc`.
+///
+/// # Examples
+/// ```rust
+/// use std::sync::Arc;
+/// use mem_btree::SharedBTree;
+///
+/// let shared = Arc::new(SharedBTree::new(32));
+/// let writer = {
+///     let shared = shared.clone();
+///     std::thread::spawn(move || {
+///         for i in 0..100 {
+///             shared.put(i, i);
+///         }
+///     })
+/// };
+/// let snapshot = shared.snapshot();
+/// assert!(snapshot.len() <= 100);
+/// writer.join().unwrap();
+/// assert_eq!(shared.snapshot().len(), 100);
+/// ```
+pub struct SharedBTree<K, V> {
+    /// serializes the writers
+    writer: Mutex<()>,
+    /// the last published tree
+    current: RwLock<BTree<K, V>>,
+}
+
+impl<K, V> SharedBTree<K, V>
+where
+    K: Ord,
+{
+    /// Create a new shared BTree with a given branching factor
+    pub fn new(m: usize) -> Self {
+        Self::from(BTree::new(m))
+    }
+
+    /// Get a snapshot of the last published tree,
+    /// later writes are not visible in it
+    pub fn snapshot(&self) -> BTree<K, V> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Get the item for a given key in the last published tree
+    pub fn get(&self, k: &K) -> Option<Item<K, V>> {
+        self.snapshot().get_item(k).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshot().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshot().is_empty()
+    }
+
+    /// Change the tree by f and publish the result when f returns.
+    /// Writers run one at a time, if f panics nothing is published
+    /// # Examples
+    /// ```rust
+    /// use mem_btree::SharedBTree;
+    /// let shared = SharedBTree::new(32);
+    /// shared.update(|tree| {
+    ///     tree.put(1, 1);
+    ///     tree.put(2, 2);
+    /// });
+    /// assert_eq!(shared.len(), 2);
+    /// ```
+    pub fn update<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut BTree<K, V>) -> R,
+    {
+        let _writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let mut tree = self.snapshot();
+        let result = f(&mut tree);
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = tree;
+        result
+    }
+
+    /// Insert a key-value pair and publish it, return the old item
+    pub fn put(&self, k: K, v: V) -> Option<Item<K, V>> {
+        self.update(|tree| tree.put(k, v))
+    }
+
+    /// Insert a key-value pair with ttl and publish it, return the old item
+    pub fn put_ttl(&self, k: K, v: V, ttl: Duration) -> Option<Item<K, V>> {
+        self.update(|tree| tree.put_ttl(k, v, ttl))
+    }
+
+    /// Remove a key and publish it, return the old item
+    pub fn remove(&self, k: &K) -> Option<Item<K, V>> {
+        self.update(|tree| tree.remove(k))
+    }
+
+    /// Write a batch and publish all of it at once
+    pub fn write(&self, batch_write: BatchWrite<K, V>) {
+        self.update(|tree| tree.write(batch_write))
+    }
+
+    /// Write a batch and publish all of it at once like `write`,
+    /// a batch the tree cannot apply is an error and nothing is published
+    pub fn try_write(&self, batch_write: BatchWrite<K, V>) -> Result<(), WriteError> {
+        self.update(|tree| tree.try_write(batch_write))
+    }
+
+    /// Write a batch and publish all of it at once,
+    /// return what the batch did to every key
+    pub fn write_with_outcomes(&self, batch_write: BatchWrite<K, V>) -> Vec<WriteOutcome<K, V>> {
+        self.update(|tree| tree.write_with_outcomes(batch_write))
+    }
+}
+
+impl<K, V> From<BTree<K, V>> for SharedBTree<K, V> {
+    fn from(tree: BTree<K, V>) -> Self {
+        Self {
+            writer: Mutex::new(()),
+            current: RwLock::new(tree),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::SharedBTree;
+    use crate::BatchWrite;
+
+    #[test]
+    fn test_snapshot_while_writing() {
+        let shared = Arc::new(SharedBTree::new(8));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        // every batch writes the same version to all keys
+                        let snapshot = shared.snapshot();
+                        let versions: Vec<_> = snapshot.iter().map(|i| i.1).collect();
+                        assert!(versions.windows(2).all(|w| w[0] == w[1]));
+                    }
+                })
+            })
+            .collect();
+
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    for version in 0..200 {
+                        let mut bw = BatchWrite::default();
+                        for k in 0..100 {
+                            bw.put(k, version);
+                        }
+                        shared.write(bw);
+                    }
+                })
+            })
+            .collect();
+
+        for w in writers {
+            w.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        for r in readers {
+            r.join().unwrap();
+        }
+
+        assert_eq!(shared.len(), 100);
+        assert_eq!(shared.get(&1).map(|i| i.1), Some(199));
+    }
+
+    #[test]
+    fn test_panic_in_update_publish_nothing() {
+        let shared = Arc::new(SharedBTree::new(8));
+        shared.put(1, 1);
+
+        let s = shared.clone();
+        let result = std::thread::spawn(move || {
+            s.update(|tree| {
+                tree.put(2, 2);
+                panic!("stop");
+            })
+        })
+        .join();
+        assert!(result.is_err());
+
+        assert_eq!(shared.len(), 1);
+        shared.put(3, 3);
+        assert_eq!(shared.len(), 2);
+    }
+}
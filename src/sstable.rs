@@ -0,0 +1,519 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    codec::{self, crc32},
+    BTree, Codec, Item,
+};
+
+/// the last bytes of a table
+const MAGIC: &[u8; 4] = b"MBST";
+
+/// the version of the format written by `BTree::write_sstable`
+const VERSION: u8 = 1;
+
+/// a block is closed after the item crossing this size
+const BLOCK_SIZE: usize = 4096;
+
+/// the index offset, the index length, the index crc32, the magic and the version
+const FOOTER_LEN: usize = 8 + 8 + 4 + 4 + 1;
+
+/// Where a block is and what it holds
+struct BlockHandle<K> {
+    /// the first key in the block, the index is sparse
+    first: K,
+    offset: u64,
+    len: u32,
+    crc: u32,
+    /// the position of the first item of the block in the table
+    start: usize,
+}
+
+/// Write the items of the tree as blocks, the index of the blocks and the footer
+pub(crate) fn write<K, V, W>(tree: &BTree<K, V>, mut w: W) -> io::Result<()>
+where
+    K: Ord + Codec + Clone,
+    V: Codec,
+    W: Write,
+{
+    let mut index: Vec<u8> = Vec::new();
+    let mut blocks = 0usize;
+    let mut offset = 0u64;
+
+    let mut block = Vec::with_capacity(BLOCK_SIZE * 2);
+    let mut first: Option<K> = None;
+    let mut count = 0usize;
+
+    let mut flush = |block: &mut Vec<u8>, first: K, count: usize, w: &mut W| -> io::Result<()> {
+        let mut data = Vec::with_capacity(block.len() + 4);
+        count.encode(&mut data)?;
+        data.append(block);
+
+        first.encode(&mut index)?;
+        offset.encode(&mut index)?;
+        (data.len() as u32).encode(&mut index)?;
+        crc32(&data).encode(&mut index)?;
+        count.encode(&mut index)?;
+
+        w.write_all(&data)?;
+        offset += data.len() as u64;
+        blocks += 1;
+        Ok(())
+    };
+
+    for item in tree.iter() {
+        if first.is_none() {
+            first = Some(item.0.clone());
+        }
+        item.2.encode(&mut block)?;
+        item.0.encode(&mut block)?;
+        item.1.encode(&mut block)?;
+        count += 1;
+
+        if block.len() >= BLOCK_SIZE {
+            flush(&mut block, first.take().unwrap(), count, &mut w)?;
+            count = 0;
+        }
+    }
+    if let Some(first) = first {
+        flush(&mut block, first, count, &mut w)?;
+    }
+
+    let mut encoded = Vec::with_capacity(index.len() + 8);
+    blocks.encode(&mut encoded)?;
+    encoded.extend_from_slice(&index);
+
+    w.write_all(&encoded)?;
+    offset.encode(&mut w)?;
+    (encoded.len() as u64).encode(&mut w)?;
+    crc32(&encoded).encode(&mut w)?;
+    w.write_all(MAGIC)?;
+    w.write_all(&[VERSION])?;
+    w.flush()
+}
+
+/// A read-only sorted table in a file, written by `BTree::write_sstable`.
+///
+/// The items are kept in blocks of about 4KB with a crc32 each, only the
+/// index of the first key of every block is in memory, a block is read when
+/// an item in it is needed.
+///
+/// # Examples
+/// ```rust
+/// use mem_btree::{BTree, SsTable};
+///
+/// let path = std::env::temp_dir().join(format!("mem_btree_sst_doc_{}", std::process::id()));
+/// let mut btree = BTree::new(32);
+/// for i in 0..1000u32 {
+///     btree.put(i, i * 2);
+/// }
+/// btree.write_sstable(&path).unwrap();
+///
+/// let table = SsTable::<u32, u32>::open(&path).unwrap();
+/// assert_eq!(table.get(&10).unwrap().map(|item| item.1), Some(20));
+/// let mut iter = table.iter();
+/// iter.seek(&998);
+/// assert_eq!(iter.next().map(|item| item.0), Some(998));
+/// assert_eq!(iter.next().map(|item| item.0), Some(999));
+/// assert_eq!(iter.next(), None);
+/// assert!(iter.status().is_ok());
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub struct SsTable<K, V> {
+    file: Mutex<File>,
+    index: Vec<BlockHandle<K>>,
+    len: usize,
+    _marker: std::marker::PhantomData<fn() -> V>,
+}
+
+impl<K, V> SsTable<K, V>
+where
+    K: Ord + Codec,
+    V: Codec,
+{
+    /// Open a table and read its index
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+
+        let file_len = file.metadata()?.len();
+        if file_len < FOOTER_LEN as u64 {
+            return Err(codec::invalid("too short for a table"));
+        }
+        file.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+        let mut footer = [0; FOOTER_LEN];
+        file.read_exact(&mut footer)?;
+
+        let mut r = &footer[..];
+        let index_offset = u64::decode(&mut r)?;
+        let index_len = u64::decode(&mut r)?;
+        let index_crc = u32::decode(&mut r)?;
+        if &r[..4] != MAGIC {
+            return Err(codec::invalid("not a table"));
+        }
+        if r[4] != VERSION {
+            return Err(codec::invalid(format!("unknown version {}", r[4])));
+        }
+        if index_offset.checked_add(index_len) != Some(file_len - FOOTER_LEN as u64) {
+            return Err(codec::invalid("bad index position"));
+        }
+
+        file.seek(SeekFrom::Start(index_offset))?;
+        let mut encoded = vec![0; index_len as usize];
+        file.read_exact(&mut encoded)?;
+        if crc32(&encoded) != index_crc {
+            return Err(codec::invalid("index checksum does not match"));
+        }
+
+        let mut r = encoded.as_slice();
+        let blocks = usize::decode(&mut r)?;
+        let mut index = Vec::with_capacity(blocks.min(1 << 16));
+        let mut len = 0;
+        for _ in 0..blocks {
+            let first = K::decode(&mut r)?;
+            let offset = u64::decode(&mut r)?;
+            let block_len = u32::decode(&mut r)?;
+            let crc = u32::decode(&mut r)?;
+            let count = usize::decode(&mut r)?;
+            index.push(BlockHandle {
+                first,
+                offset,
+                len: block_len,
+                crc,
+                start: len,
+            });
+            len += count;
+        }
+
+        Ok(Self {
+            file: Mutex::new(file),
+            index,
+            len,
+            _marker: std::marker::PhantomData,
+        })
+    }
+
+    /// read and check a block
+    fn read_block(&self, block: usize) -> io::Result<Vec<Item<K, V>>> {
+        let handle = &self.index[block];
+        let mut data = vec![0; handle.len as usize];
+        {
+            let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut data)?;
+        }
+        if crc32(&data) != handle.crc {
+            return Err(codec::invalid(format!(
+                "checksum of block {block} does not match"
+            )));
+        }
+
+        let mut r = data.as_slice();
+        let count = usize::decode(&mut r)?;
+        let mut items = Vec::with_capacity(count.min(BLOCK_SIZE));
+        for _ in 0..count {
+            let ttl = Option::decode(&mut r)?;
+            let k = K::decode(&mut r)?;
+            let v = V::decode(&mut r)?;
+            items.push(Arc::new((k, v, ttl)));
+        }
+        Ok(items)
+    }
+
+    /// the block which may hold k, the last one whose first key is not greater than k
+    fn block_of(&self, k: &K) -> Option<usize> {
+        self.index.partition_point(|h| h.first <= *k).checked_sub(1)
+    }
+
+    /// the block holding the item at the position
+    fn block_at(&self, position: usize) -> usize {
+        self.index.partition_point(|h| h.start <= position) - 1
+    }
+
+    /// Get the item for a given key, reading one block
+    pub fn get(&self, k: &K) -> io::Result<Option<Item<K, V>>> {
+        let Some(block) = self.block_of(k) else {
+            return Ok(None);
+        };
+        let items = self.read_block(block)?;
+        Ok(items
+            .binary_search_by(|item| item.0.cmp(k))
+            .ok()
+            .map(|i| items[i].clone()))
+    }
+
+    /// Get the number of items in the table
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterate the table, it moves like the `Iter` of a BTree
+    pub fn iter(&self) -> SsIter<'_, K, V> {
+        SsIter {
+            table: self,
+            position: None,
+            block: None,
+            error: None,
+        }
+    }
+}
+
+/// An iterator over a SsTable, with `next`, `prev`, `seek` and `seek_prev` as `Iter`.
+///
+/// A block which can not be read ends the iteration,
+/// the error is returned by `status`
+pub struct SsIter<'a, K, V> {
+    table: &'a SsTable<K, V>,
+    /// the position of the last returned item, none before the first move,
+    /// then next returns the first item and prev the last one
+    position: Option<isize>,
+    /// the last block read, and its number
+    block: Option<(usize, Vec<Item<K, V>>)>,
+    error: Option<io::Error>,
+}
+
+impl<K, V> SsIter<'_, K, V>
+where
+    K: Ord + Codec,
+    V: Codec,
+{
+    /// the block, read if it is not the last block read
+    fn load(&mut self, block: usize) -> Option<&Vec<Item<K, V>>> {
+        if self.error.is_some() {
+            return None;
+        }
+        if !matches!(&self.block, Some((b, _)) if *b == block) {
+            match self.table.read_block(block) {
+                Ok(items) => self.block = Some((block, items)),
+                Err(e) => {
+                    self.error = Some(e);
+                    return None;
+                }
+            }
+        }
+        self.block.as_ref().map(|(_, items)| items)
+    }
+
+    /// the item at the position, it becomes the position of the iterator
+    fn item_at(&mut self, position: isize) -> Option<Item<K, V>> {
+        if position < 0 || position as usize >= self.table.len {
+            self.position = Some(position.clamp(-1, self.table.len as isize));
+            return None;
+        }
+        let block = self.table.block_at(position as usize);
+        let start = self.table.index[block].start;
+        let item = self.load(block)?[position as usize - start].clone();
+        self.position = Some(position);
+        Some(item)
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<Item<K, V>> {
+        let position = self.position.map_or(0, |p| p + 1);
+        self.item_at(position)
+    }
+
+    pub fn prev(&mut self) -> Option<Item<K, V>> {
+        let position = self.position.map_or(self.table.len as isize - 1, |p| p - 1);
+        self.item_at(position)
+    }
+
+    /// the iterator is the same as a new one
+    pub fn reset(&mut self) {
+        self.position = None;
+    }
+
+    /// seek by the key, next returns the first item not less than key
+    pub fn seek(&mut self, key: &K) {
+        let position = match self.table.block_of(key) {
+            None => 0,
+            Some(block) => {
+                let start = self.table.index[block].start;
+                match self.load(block) {
+                    Some(items) => start + items.partition_point(|item| item.0 < *key),
+                    None => return,
+                }
+            }
+        };
+        self.position = Some(position as isize - 1);
+    }
+
+    /// seek prev by the key, prev returns the last item not greater than key
+    pub fn seek_prev(&mut self, key: &K) {
+        let position = match self.table.block_of(key) {
+            None => -1,
+            Some(block) => {
+                let start = self.table.index[block].start;
+                match self.load(block) {
+                    // the first key of the block is not greater than key
+                    Some(items) => {
+                        (start + items.partition_point(|item| item.0 <= *key)) as isize - 1
+                    }
+                    None => return,
+                }
+            }
+        };
+        self.position = Some(position + 1);
+    }
+
+    /// the error which ended the iteration, if a block could not be read
+    pub fn status(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<K, V> Iterator for SsIter<'_, K, V>
+where
+    K: Ord + Codec,
+    V: Codec,
+{
+    type Item = Item<K, V>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next()
+    }
+}
+
+/// create the file and write the table into it
+pub(crate) fn create<K, V, P>(tree: &BTree<K, V>, path: P) -> io::Result<()>
+where
+    K: Ord + Codec + Clone,
+    V: Codec,
+    P: AsRef<Path>,
+{
+    let file = File::create(path)?;
+    let mut w = BufWriter::new(file);
+    write(tree, &mut w)?;
+    w.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::SsTable;
+    use crate::BTree;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mem_btree_sst_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_sstable_same_as_btree() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let path = temp_path("same");
+
+        let mut btree = BTree::new(32);
+        for _ in 0..20000 {
+            let k = rng.gen_range(0..100000u32);
+            btree.put(k, format!("value {k}"));
+        }
+        btree.write_sstable(&path).unwrap();
+        let table = SsTable::<u32, String>::open(&path).unwrap();
+        assert_eq!(table.len(), btree.len());
+        assert!(table.index.len() > 10);
+
+        for _ in 0..1000 {
+            let k = rng.gen_range(0..100001u32);
+            assert_eq!(table.get(&k).unwrap().as_ref(), btree.get_item(&k));
+        }
+
+        let all: Vec<_> = btree.iter().collect();
+        assert_eq!(table.iter().collect::<Vec<_>>(), all);
+        let mut iter = table.iter();
+        let mut reversed = Vec::new();
+        while let Some(item) = iter.prev() {
+            reversed.push(item);
+        }
+        reversed.reverse();
+        assert_eq!(reversed, all);
+
+        for _ in 0..1000 {
+            let k = rng.gen_range(0..100001u32);
+
+            let mut expected = btree.iter();
+            let mut iter = table.iter();
+            expected.seek(&k);
+            iter.seek(&k);
+            for _ in 0..5 {
+                assert_eq!(iter.next(), expected.next());
+            }
+
+            expected.seek_prev(&k);
+            iter.seek_prev(&k);
+            for _ in 0..5 {
+                assert_eq!(iter.prev(), expected.prev());
+            }
+
+            // a walk back and forth from the key
+            iter.seek(&k);
+            let i = all.partition_point(|item| item.0 < k) as isize - 1;
+            let mut i = i;
+            for _ in 0..20 {
+                if rng.gen_bool(0.5) {
+                    i += 1;
+                    assert_eq!(iter.next().as_ref(), all.get(i as usize));
+                } else {
+                    i -= 1;
+                    assert_eq!(
+                        iter.prev().as_ref(),
+                        usize::try_from(i).ok().and_then(|i| all.get(i))
+                    );
+                }
+                i = i.clamp(-1, all.len() as isize);
+            }
+            assert!(iter.status().is_ok());
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sstable_empty_and_corrupted() {
+        let path = temp_path("corrupted");
+
+        let empty: BTree<u32, u32> = BTree::new(8);
+        empty.write_sstable(&path).unwrap();
+        let table = SsTable::<u32, u32>::open(&path).unwrap();
+        assert!(table.is_empty());
+        assert_eq!(table.get(&1).unwrap(), None);
+        assert_eq!(table.iter().next(), None);
+        assert_eq!(table.iter().prev(), None);
+
+        let mut btree = BTree::new(8);
+        for i in 0..5000u32 {
+            btree.put(i, i);
+        }
+        btree.write_sstable(&path).unwrap();
+
+        let mut data = std::fs::read(&path).unwrap();
+        data[10] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+        let table = SsTable::<u32, u32>::open(&path).unwrap();
+        assert!(table.get(&0).is_err());
+        assert_eq!(table.get(&4999).unwrap().map(|i| i.1), Some(4999));
+        let mut iter = table.iter();
+        assert_eq!(iter.next(), None);
+        assert!(iter.status().is_err());
+
+        let len = data.len();
+        data[len - 1] = 9;
+        std::fs::write(&path, &data).unwrap();
+        assert!(SsTable::<u32, u32>::open(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
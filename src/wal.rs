@@ -0,0 +1,377 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::Path,
+};
+
+use crate::{
+    codec::{self, crc32},
+    BTree, BatchWrite, Codec,
+};
+
+/// the first bytes of a log file
+const MAGIC: &[u8; 4] = b"MBWL";
+
+/// the version of the records written by `Wal`
+const VERSION: u8 = 1;
+
+/// the magic and the version
+const HEADER_LEN: u64 = 5;
+
+/// a record is its payload length, the crc32 of the payload and the payload
+const RECORD_HEADER_LEN: usize = 8;
+
+/// A write-ahead log of batches.
+///
+/// Every batch is encoded by `Codec` with a crc32 checksum and synced to the
+/// file before it is applied to the tree, so an acknowledged batch survives a
+/// crash. `recover` replays the intact records and cuts off a record torn by
+/// a crash in the middle of an append, a damaged record followed by more
+/// records is an error.
+///
+/// # Examples
+/// ```rust
+/// use mem_btree::{BTree, BatchWrite, Wal};
+///
+/// let path = std::env::temp_dir().join(format!("mem_btree_wal_doc_{}", std::process::id()));
+/// let _ = std::fs::remove_file(&path);
+///
+/// let mut btree = BTree::new(32);
+/// let mut wal = Wal::recover(&path, &mut btree).unwrap();
+/// let mut bw = BatchWrite::default();
+/// bw.put(1u32, String::from("a"));
+/// wal.write(&mut btree, bw).unwrap();
+/// drop(wal);
+///
+/// let mut recovered = BTree::new(32);
+/// Wal::<u32, String>::recover(&path, &mut recovered).unwrap();
+/// assert_eq!(recovered.get(&1), Some(&String::from("a")));
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub struct Wal<K, V> {
+    file: File,
+    /// the end of the last intact record
+    end: u64,
+    _marker: PhantomData<fn(BatchWrite<K, V>)>,
+}
+
+impl<K, V> Wal<K, V>
+where
+    K: Codec + Ord,
+    V: Codec + PartialEq,
+{
+    /// Open the log at path, or create it, and apply its records to the tree.
+    /// A record torn at the end of the log is truncated, the returned log
+    /// appends after the last intact record. A record failing its checksum
+    /// with more bytes after it is `InvalidData`, so is a file without the header
+    pub fn recover<P: AsRef<Path>>(path: P, tree: &mut BTree<K, V>) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let len = file.metadata()?.len();
+        let end = if len == 0 {
+            // a new log, any other file must start with the header
+            file.write_all(MAGIC)?;
+            file.write_all(&[VERSION])?;
+            HEADER_LEN
+        } else {
+            Self::replay(&mut file, tree)?
+        };
+
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+        file.sync_all()?;
+
+        Ok(Self {
+            file,
+            end,
+            _marker: PhantomData,
+        })
+    }
+
+    /// apply the intact records to the tree, return the end of the last one
+    fn replay(file: &mut File, tree: &mut BTree<K, V>) -> io::Result<u64> {
+        let mut r = BufReader::new(&mut *file);
+
+        let mut header = [0; HEADER_LEN as usize];
+        if read_full(&mut r, &mut header)? < header.len() || &header[..4] != MAGIC {
+            return Err(codec::invalid("not a write-ahead log"));
+        }
+        if header[4] != VERSION {
+            return Err(codec::invalid(format!("unknown version {}", header[4])));
+        }
+
+        let mut end = HEADER_LEN;
+        let mut payload = Vec::new();
+        loop {
+            let mut record_header = [0; RECORD_HEADER_LEN];
+            if read_full(&mut r, &mut record_header)? < RECORD_HEADER_LEN {
+                return Ok(end);
+            }
+            let len = u32::from_le_bytes(record_header[..4].try_into().unwrap()) as usize;
+            let crc = u32::from_le_bytes(record_header[4..].try_into().unwrap());
+
+            payload.clear();
+            (&mut r).take(len as u64).read_to_end(&mut payload)?;
+            if payload.len() < len {
+                return Ok(end);
+            }
+            if crc32(&payload) != crc {
+                // only the last record can be torn by a crash,
+                // the records after a damaged one are lost otherwise
+                if !r.fill_buf()?.is_empty() {
+                    return Err(codec::invalid(format!("damaged record at {end}")));
+                }
+                return Ok(end);
+            }
+
+            // the checksum holds, a payload failing to decode is not a torn write
+            let batch_write = BatchWrite::decode(&mut payload.as_slice())?;
+            tree.try_write(batch_write).map_err(codec::invalid)?;
+            end += (RECORD_HEADER_LEN + len) as u64;
+        }
+    }
+
+    /// Append the batch to the log and sync it to the disk
+    pub fn append(&mut self, batch_write: &BatchWrite<K, V>) -> io::Result<()> {
+        let mut record = vec![0; RECORD_HEADER_LEN];
+        batch_write.encode(&mut record)?;
+
+        let len = u32::try_from(record.len() - RECORD_HEADER_LEN)
+            .map_err(|_| codec::invalid("batch is too large for a record"))?;
+        let crc = crc32(&record[RECORD_HEADER_LEN..]);
+        record[..4].copy_from_slice(&len.to_le_bytes());
+        record[4..RECORD_HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+
+        let written = self
+            .file
+            .write_all(&record)
+            .and_then(|_| self.file.sync_data());
+        if let Err(e) = written {
+            // cut a partly written record, the later records must follow an intact one
+            let _ = self.file.set_len(self.end);
+            let _ = self.file.seek(SeekFrom::Start(self.end));
+            return Err(e);
+        }
+        self.end += record.len() as u64;
+        Ok(())
+    }
+
+    /// Append the batch to the log, then apply it to the tree.
+    /// A batch the tree cannot apply is `InvalidInput` and is not appended,
+    /// the tree is not changed if the append fails
+    pub fn write(
+        &mut self,
+        tree: &mut BTree<K, V>,
+        batch_write: BatchWrite<K, V>,
+    ) -> io::Result<()> {
+        tree.check_write(&batch_write)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.append(&batch_write)?;
+        tree.write(batch_write);
+        Ok(())
+    }
+
+    /// Empty the log, after the tree was persisted some other way
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.file.set_len(HEADER_LEN)?;
+        self.file.seek(SeekFrom::Start(HEADER_LEN))?;
+        self.end = HEADER_LEN;
+        self.file.sync_all()
+    }
+}
+
+/// read until buf is full or the end of r, return the number of bytes read
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(i) => n += i,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        fs::OpenOptions,
+        io::Write,
+        path::{Path, PathBuf},
+    };
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::Wal;
+    use crate::{BTree, BatchWrite};
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("mem_btree_wal_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn recover(path: &Path) -> (BTree<u32, String>, Wal<u32, String>) {
+        let mut tree = BTree::new(8);
+        let wal = Wal::recover(path, &mut tree).unwrap();
+        (tree, wal)
+    }
+
+    fn collect(tree: &BTree<u32, String>) -> BTreeMap<u32, String> {
+        tree.iter().map(|i| (i.0, i.1.clone())).collect()
+    }
+
+    #[test]
+    fn test_wal_recover() {
+        let path = temp_path("recover");
+        let mut rng = StdRng::seed_from_u64(42);
+
+        let (mut tree, mut wal) = recover(&path);
+        for _ in 0..50 {
+            let mut bw = BatchWrite::default();
+            let start = rng.gen_range(0..1000);
+            bw.delete_range(start..start + 50);
+            for _ in 0..20 {
+                let k = rng.gen_range(0..1000);
+                match rng.gen_range(0..4) {
+                    0 => bw.delete(k),
+                    1 => bw.compare_and_set(k, String::new(), format!("cas{k}")),
+                    2 => bw.put_if_absent(k, String::new()),
+                    _ => bw.put(k, format!("v{k}")),
+                }
+            }
+            wal.write(&mut tree, bw).unwrap();
+        }
+        drop(wal);
+
+        let (recovered, mut wal) = recover(&path);
+        assert_eq!(collect(&recovered), collect(&tree));
+
+        // appends go after the recovered records
+        let mut bw = BatchWrite::default();
+        bw.put(5000, String::from("last"));
+        wal.write(&mut tree, bw).unwrap();
+        drop(wal);
+        let (recovered, _) = recover(&path);
+        assert_eq!(collect(&recovered), collect(&tree));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_wal_rejects_merge_without_operator() {
+        let path = temp_path("merge");
+
+        let (mut tree, mut wal) = recover(&path);
+        let len = std::fs::metadata(&path).unwrap().len();
+        let mut bw = BatchWrite::default();
+        bw.put(1, String::from("a"));
+        bw.merge(1, String::from("b"));
+        let err = wal.write(&mut tree, bw).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(tree.is_empty());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+        let mut bw = BatchWrite::default();
+        bw.put(2, String::from("c"));
+        wal.write(&mut tree, bw).unwrap();
+        drop(wal);
+        let (recovered, _) = recover(&path);
+        assert_eq!(collect(&recovered), collect(&tree));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_wal_torn_tail() {
+        let path = temp_path("torn");
+
+        let (mut tree, mut wal) = recover(&path);
+        let mut lens = vec![std::fs::metadata(&path).unwrap().len()];
+        for i in 0..10 {
+            let mut bw = BatchWrite::default();
+            bw.put(i, format!("v{i}"));
+            wal.write(&mut tree, bw).unwrap();
+            lens.push(std::fs::metadata(&path).unwrap().len());
+        }
+        drop(wal);
+
+        // a crash in the middle of the last append
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(lens[10] - 3).unwrap();
+        drop(file);
+
+        let (recovered, wal) = recover(&path);
+        assert_eq!(recovered.len(), 9);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), lens[9]);
+        drop(wal);
+
+        // garbage after the records
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[7; 30]).unwrap();
+        drop(file);
+        let (recovered, mut wal) = recover(&path);
+        assert_eq!(recovered.len(), 9);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), lens[9]);
+
+        wal.reset().unwrap();
+        drop(wal);
+        let (recovered, _) = recover(&path);
+        assert!(recovered.is_empty());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), lens[0]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_wal_damaged_record() {
+        let path = temp_path("damaged");
+
+        let (mut tree, mut wal) = recover(&path);
+        let mut lens = vec![std::fs::metadata(&path).unwrap().len()];
+        for i in 0..3 {
+            let mut bw = BatchWrite::default();
+            bw.put(i, format!("v{i}"));
+            wal.write(&mut tree, bw).unwrap();
+            lens.push(std::fs::metadata(&path).unwrap().len());
+        }
+        drop(wal);
+        let flip = |pos: u64| {
+            let mut bytes = std::fs::read(&path).unwrap();
+            bytes[pos as usize] ^= 0xff;
+            std::fs::write(&path, bytes).unwrap();
+        };
+
+        // the last record is cut as a torn write
+        flip(lens[3] - 1);
+        let (recovered, wal) = recover(&path);
+        assert_eq!(recovered.len(), 2);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), lens[2]);
+        drop(wal);
+
+        // a damaged record before intact ones is not cut
+        flip(lens[1] - 1);
+        let mut tree = BTree::new(8);
+        let err = Wal::<u32, String>::recover(&path, &mut tree).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), lens[2]);
+
+        // a short file that is not a log is not overwritten
+        std::fs::write(&path, b"abc").unwrap();
+        let err = Wal::<u32, String>::recover(&path, &mut tree).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), b"abc");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}